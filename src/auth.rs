@@ -1,18 +1,19 @@
-use futures_util::StreamExt;
+use anyhow::{bail, Context};
+use log::{error, warn};
 use matrix_sdk::{
-    encryption::verification::{
-        format_emojis, Emoji, SasState, SasVerification, Verification, VerificationRequest,
-        VerificationRequestState,
-    },
-    matrix_auth::MatrixSession,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
     ruma::{
-        events::{
-            key::verification::request::ToDeviceKeyVerificationRequestEvent,
-            room::message::{MessageType, OriginalSyncRoomMessageEvent},
+        api::client::{
+            account::whoami,
+            session::{
+                get_login_types::v3::LoginType,
+                login::{self, v3::LoginInfo},
+            },
+            uiaa::{AuthData, Password, UiaaInfo, UserIdentifier},
         },
-        UserId,
+        OwnedDeviceId, OwnedUserId, UserId,
     },
-    Client,
+    Client, SessionChange, SessionMeta,
 };
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
@@ -20,40 +21,72 @@ use std::{
     io::{self, Write},
     path::{Path, PathBuf},
 };
-use tokio::fs;
+use tokio::{fs, sync::broadcast::error::RecvError};
+
+use crate::{error::BotError, secrets};
+
+/// The display name of the devices the bot logs in with.
+pub const DEVICE_DISPLAY_NAME: &str = "oxybot client";
+
+/// Read the persisted session without restoring it.
+///
+/// If the session can't be read, it is replaced by its backup.
+pub async fn load_session(session_file: &Path) -> anyhow::Result<FullSession> {
+    let error = match read_session(session_file).await {
+        Ok(full_session) => return Ok(full_session),
+        Err(error) => error,
+    };
+
+    let backup = match read_backup_session(session_file).await {
+        Ok(backup) => backup,
+        Err(backup_error) => {
+            return Err(error.context(format!(
+                "The session in '{}' is unreadable and so is its backup ({backup_error:#}). \
+                 Move it away and log in again with `oxybot login`",
+                session_file.to_string_lossy()
+            )))
+        }
+    };
 
-/// Persist the sync token for a future session.
-/// Note that this is needed only when using `sync_once`. Other sync methods get
-/// the sync token from the store.
-pub async fn persist_sync_token(session_file: &Path, sync_token: String) -> anyhow::Result<()> {
-    let serialized_session = fs::read_to_string(session_file).await?;
-    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+    warn!(
+        "The session in '{}' is unreadable, restoring its backup: {error:#}",
+        session_file.to_string_lossy()
+    );
+    secrets::restore_backup(session_file).await?;
 
-    full_session.sync_token = Some(sync_token);
-    let serialized_session = serde_json::to_string(&full_session)?;
-    fs::write(session_file, serialized_session).await?;
+    Ok(backup)
+}
 
-    Ok(())
+async fn read_session(session_file: &Path) -> anyhow::Result<FullSession> {
+    // The session was serialized as JSON in an encrypted file.
+    let serialized_session = secrets::read(session_file).await?;
+    Ok(serde_json::from_str(&serialized_session)?)
+}
+
+async fn read_backup_session(session_file: &Path) -> anyhow::Result<FullSession> {
+    let serialized_session = secrets::read_backup(session_file).await?;
+    Ok(serde_json::from_str(&serialized_session)?)
 }
 
 /// Restore a previous session.
+///
+/// Returns the client and the sync token of sessions from older versions.
 pub async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<String>)> {
     println!(
         "Previous session found in '{}'",
         session_file.to_string_lossy()
     );
 
-    // The session was serialized as JSON in a file.
-    let serialized_session = fs::read_to_string(session_file).await?;
     let FullSession {
         client_session,
         user_session,
         sync_token,
-    } = serde_json::from_str(&serialized_session)?;
+    } = load_session(session_file).await?;
 
     // Build the client with the previous settings from the session.
     let client = Client::builder()
         .homeserver_url(client_session.homeserver)
+        .handle_refresh_tokens()
         .sqlite_store(client_session.db_path, Some(&client_session.passphrase))
         .build()
         .await?;
@@ -66,10 +99,311 @@ pub async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Opt
     Ok((client, sync_token))
 }
 
+/// Log out the persisted session and delete it, along with its store.
+///
+/// The local data is removed even if the homeserver can't be reached, so the
+/// device might have to be removed manually in that case.
+pub async fn logout(session_file: &Path) -> anyhow::Result<()> {
+    let FullSession {
+        client_session,
+        user_session,
+        ..
+    } = load_session(session_file).await?;
+
+    println!("Logging out {}…", user_session.meta.user_id);
+
+    let client = Client::builder()
+        .homeserver_url(&client_session.homeserver)
+        .handle_refresh_tokens()
+        .sqlite_store(&client_session.db_path, Some(&client_session.passphrase))
+        .build()
+        .await?;
+    client.restore_session(user_session).await?;
+
+    if let Err(error) = client.matrix_auth().logout().await {
+        println!("Error invalidating the device on the homeserver: {error}");
+    }
+
+    // Drop the client first so the store is closed before removing it.
+    drop(client);
+
+    fs::remove_file(session_file).await?;
+    let backup = secrets::backup_path(session_file);
+    if backup.exists() {
+        fs::remove_file(backup).await?;
+    }
+    secrets::forget(session_file).await?;
+    if client_session.db_path.exists() {
+        fs::remove_dir_all(&client_session.db_path).await?;
+    }
+
+    println!("Session and store deleted");
+
+    Ok(())
+}
+
+/// Get a new access token after the homeserver invalidated it, and persist it.
+///
+/// The refresh token is tried first, if there is one. Otherwise the device is
+/// logged in again with the credentials, which is only possible after a soft
+/// logout: a hard logout deleted the device and its keys.
+///
+/// A refreshed token is used by `client` right away. After a login, the
+/// client has to be restored again from the session file.
+pub async fn reauthenticate(
+    client: &Client,
+    session_file: &Path,
+    credentials: Option<Credentials>,
+    soft_logout: bool,
+) -> anyhow::Result<Reauthenticated> {
+    let matrix_auth = client.matrix_auth();
+    let session = matrix_auth.session().ok_or(BotError::NotLoggedIn)?;
+
+    if session.tokens.refresh_token.is_some() {
+        match matrix_auth.refresh_access_token().await {
+            Ok(_) => {
+                let tokens = matrix_auth.session_tokens().ok_or(BotError::NotLoggedIn)?;
+                persist_session_tokens(session_file, tokens).await?;
+                println!("Access token refreshed");
+                return Ok(Reauthenticated::Refreshed);
+            }
+            Err(error) => println!("Error refreshing the access token: {error}"),
+        }
+    }
+
+    if !soft_logout {
+        bail!(
+            "The homeserver logged out the device {}, it can't be used anymore",
+            session.meta.device_id
+        );
+    }
+
+    let tokens = match credentials.map(|credentials| credentials.method) {
+        Some(LoginMethod::Password { username, password }) => {
+            let mut request = login::v3::Request::new(LoginInfo::Password(
+                login::v3::Password::new(UserIdentifier::UserIdOrLocalpart(username), password),
+            ));
+            // Keep the device, so its keys stay valid.
+            request.device_id = Some(session.meta.device_id.clone());
+            request.refresh_token = true;
+
+            let response = client
+                .send(request, None)
+                .await
+                .context("Error logging in again")?;
+            if response.user_id != session.meta.user_id {
+                bail!(
+                    "The credentials are for {}, not {}",
+                    response.user_id,
+                    session.meta.user_id
+                );
+            }
+
+            MatrixSessionTokens {
+                access_token: response.access_token,
+                refresh_token: response.refresh_token,
+            }
+        }
+        Some(LoginMethod::AccessToken {
+            user_id,
+            device_id,
+            access_token,
+        }) => {
+            if user_id != session.meta.user_id || device_id != session.meta.device_id {
+                bail!("The access token is for another user or device than the session");
+            }
+            if access_token == session.tokens.access_token {
+                bail!("The access token is the one the homeserver invalidated");
+            }
+
+            MatrixSessionTokens {
+                access_token,
+                refresh_token: None,
+            }
+        }
+        None => bail!(
+            "No credentials to log in again with, set OXYBOT_PASSWORD or OXYBOT_PASSWORD_FILE"
+        ),
+    };
+
+    persist_session_tokens(session_file, tokens).await?;
+    println!("Logged in again as {}", session.meta.user_id);
+
+    Ok(Reauthenticated::LoggedIn)
+}
+
+/// How [`reauthenticate`] got a new access token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reauthenticated {
+    /// The client refreshed it and can keep syncing.
+    Refreshed,
+
+    /// The device logged in again, and the new tokens are only in the session
+    /// file: a logged-in client can't take them. The old client must be
+    /// dropped before restoring a new one, so that two crypto machines never
+    /// share the store.
+    LoggedIn,
+}
+
+/// Persist the tokens of the session each time the client refreshes them.
+///
+/// An invalidated token is handled by the sync loop, see [`reauthenticate`].
+pub async fn persist_session_changes(client: Client, session_file: PathBuf) {
+    let mut changes = client.subscribe_to_session_changes();
+
+    loop {
+        match changes.recv().await {
+            Ok(SessionChange::TokensRefreshed) | Err(RecvError::Lagged(_)) => {
+                let Some(tokens) = client.matrix_auth().session_tokens() else {
+                    continue;
+                };
+                if let Err(error) = persist_session_tokens(&session_file, tokens).await {
+                    error!("Unable to persist the refreshed access token: {error:#}");
+                }
+            }
+            Ok(SessionChange::UnknownToken { .. }) => (),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Replace the tokens of the persisted session.
+async fn persist_session_tokens(
+    session_file: &Path,
+    tokens: MatrixSessionTokens,
+) -> anyhow::Result<()> {
+    let mut full_session = load_session(session_file).await?;
+    full_session.user_session.tokens = tokens;
+
+    let serialized_session = serde_json::to_string(&full_session)?;
+    secrets::write(session_file, &serialized_session).await
+}
+
+/// Credentials for a login that doesn't prompt for anything.
+pub struct Credentials {
+    /// The URL of the homeserver of the user.
+    pub homeserver: String,
+
+    /// How to authenticate with the homeserver.
+    pub method: LoginMethod,
+}
+
+/// The ways to log in without user interaction.
+pub enum LoginMethod {
+    /// Log in with a username or user ID and a password.
+    Password { username: String, password: String },
+
+    /// Use a pre-issued access token for an existing device.
+    AccessToken {
+        user_id: OwnedUserId,
+        device_id: OwnedDeviceId,
+        access_token: String,
+    },
+}
+
 /// Login with a new device.
-pub async fn login(data_dir: &Path, session_file: &Path) -> anyhow::Result<Client> {
+///
+/// Without credentials, the homeserver, username and password are asked on
+/// stdin until the login succeeds. With credentials, the first error is
+/// returned instead.
+pub async fn login(
+    data_dir: &Path,
+    session_file: &Path,
+    credentials: Option<Credentials>,
+) -> anyhow::Result<Client> {
     println!("No previous session found, logging in…");
 
+    let (client, client_session) = match credentials {
+        Some(credentials) => login_with_credentials(data_dir, credentials).await?,
+        None => login_interactive(data_dir).await?,
+    };
+
+    persist_new_session(session_file, &client, client_session).await?;
+
+    Ok(client)
+}
+
+/// Login with a new device through the single sign-on of the homeserver.
+///
+/// The homeserver is asked on stdin if it isn't given. The SSO page redirects
+/// to a temporary local HTTP server, which receives the login token.
+pub async fn login_sso(
+    data_dir: &Path,
+    session_file: &Path,
+    homeserver: Option<String>,
+) -> anyhow::Result<Client> {
+    println!("No previous session found, logging in with SSO…");
+
+    let (client, client_session) = match homeserver {
+        Some(homeserver) => build_client_for(data_dir, homeserver).await?,
+        None => build_client(data_dir).await?,
+    };
+    let matrix_auth = client.matrix_auth();
+
+    let login_types = matrix_auth
+        .get_login_types()
+        .await
+        .context("Error getting the login types of the homeserver")?;
+    if !login_types
+        .flows
+        .iter()
+        .any(|flow| matches!(flow, LoginType::Sso(_)))
+    {
+        bail!(
+            "The homeserver {} doesn't support SSO",
+            client_session.homeserver.trim()
+        );
+    }
+
+    matrix_auth
+        .login_sso(|sso_url| async move {
+            println!("\nOpen this URL in a browser to log in:\n\n{sso_url}\n");
+            Ok(())
+        })
+        .initial_device_display_name(DEVICE_DISPLAY_NAME)
+        .request_refresh_token()
+        .await
+        .context("Error logging in with SSO")?;
+
+    let user_id = client
+        .user_id()
+        .context("The homeserver didn't tell the user ID of the session")?;
+    println!("Logged in as {user_id}");
+
+    persist_new_session(session_file, &client, client_session).await?;
+
+    Ok(client)
+}
+
+/// Persist the session of a client that just logged in.
+async fn persist_new_session(
+    session_file: &Path,
+    client: &Client,
+    client_session: ClientSession,
+) -> anyhow::Result<()> {
+    // Persist the session to reuse it later.
+    // It is encrypted with a key from the system keyring, or derived from a
+    // passphrase when there is none, since it holds the access token and the
+    // passphrase of the store.
+    // Note that we could also build the user session from the login response.
+    let user_session = client
+        .matrix_auth()
+        .session()
+        .expect("A logged-in client should have a session");
+    let serialized_session = serde_json::to_string(&FullSession {
+        client_session,
+        user_session,
+        sync_token: None,
+    })?;
+    secrets::write(session_file, &serialized_session).await?;
+
+    println!("Session persisted in {}", session_file.to_string_lossy());
+
+    Ok(())
+}
+
+/// Login by asking the user for the homeserver and credentials on stdin.
+async fn login_interactive(data_dir: &Path) -> anyhow::Result<(Client, ClientSession)> {
     let (client, client_session) = build_client(data_dir).await?;
     let matrix_auth = client.matrix_auth();
 
@@ -82,17 +416,12 @@ pub async fn login(data_dir: &Path, session_file: &Path) -> anyhow::Result<Clien
             .expect("Unable to read user input");
         username = username.trim().to_owned();
 
-        print!("Password: ");
-        io::stdout().flush().expect("Unable to write to stdout");
-        let mut password = String::new();
-        io::stdin()
-            .read_line(&mut password)
-            .expect("Unable to read user input");
-        password = password.trim().to_owned();
+        let password = prompt_secret("Password: ").expect("Unable to read user input");
 
         match matrix_auth
             .login_username(&username, &password)
-            .initial_device_display_name("oxybot client")
+            .initial_device_display_name(DEVICE_DISPLAY_NAME)
+            .request_refresh_token()
             .await
         {
             Ok(_) => {
@@ -106,27 +435,83 @@ pub async fn login(data_dir: &Path, session_file: &Path) -> anyhow::Result<Clien
         }
     }
 
-    // Persist the session to reuse it later.
-    // This is not very secure, for simplicity. If the system provides a way of
-    // storing secrets securely, it should be used instead.
-    // Note that we could also build the user session from the login response.
-    let user_session = matrix_auth
-        .session()
-        .expect("A logged-in client should have a session");
-    let serialized_session = serde_json::to_string(&FullSession {
-        client_session,
-        user_session,
-        sync_token: None,
-    })?;
-    fs::write(session_file, serialized_session).await?;
+    Ok((client, client_session))
+}
 
-    println!("Session persisted in {}", session_file.to_string_lossy());
+/// Login with the given credentials, failing on the first error.
+async fn login_with_credentials(
+    data_dir: &Path,
+    credentials: Credentials,
+) -> anyhow::Result<(Client, ClientSession)> {
+    let (client, client_session) = build_client_for(data_dir, credentials.homeserver).await?;
+
+    match credentials.method {
+        LoginMethod::Password { username, password } => {
+            client
+                .matrix_auth()
+                .login_username(&username, &password)
+                .initial_device_display_name(DEVICE_DISPLAY_NAME)
+                .request_refresh_token()
+                .await
+                .with_context(|| format!("Error logging in as {username}"))?;
+        }
+        LoginMethod::AccessToken {
+            user_id,
+            device_id,
+            access_token,
+        } => {
+            client
+                .restore_session(MatrixSession {
+                    meta: SessionMeta { user_id, device_id },
+                    tokens: MatrixSessionTokens {
+                        access_token,
+                        refresh_token: None,
+                    },
+                })
+                .await?;
 
-    Ok(client)
+            // Restoring doesn't talk to the homeserver, make sure the token is valid.
+            client
+                .send(whoami::v3::Request::new(), None)
+                .await
+                .context("The access token was rejected by the homeserver")?;
+        }
+    }
+
+    let user_id = client
+        .user_id()
+        .context("The homeserver didn't tell the user ID of the session")?;
+    println!("Logged in as {user_id}");
+
+    Ok((client, client_session))
 }
 
-/// Build a new client.
-async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSession)> {
+/// Build a new client for the given homeserver.
+async fn build_client_for(
+    data_dir: &Path,
+    homeserver: String,
+) -> anyhow::Result<(Client, ClientSession)> {
+    let (db_path, passphrase) = new_store(data_dir);
+    let client = Client::builder()
+        .homeserver_url(&homeserver)
+        .handle_refresh_tokens()
+        .sqlite_store(&db_path, Some(&passphrase))
+        .build()
+        .await
+        .with_context(|| format!("Error checking the homeserver {homeserver}"))?;
+
+    Ok((
+        client,
+        ClientSession {
+            homeserver,
+            db_path,
+            passphrase,
+        },
+    ))
+}
+
+/// Generate the path and passphrase of a new store.
+fn new_store(data_dir: &Path) -> (PathBuf, String) {
     let mut rng = thread_rng();
 
     // Generating a subfolder for the database is not mandatory, but it is useful if
@@ -146,6 +531,13 @@ async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSession)
         .map(char::from)
         .collect();
 
+    (db_path, passphrase)
+}
+
+/// Build a new client.
+async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSession)> {
+    let (db_path, passphrase) = new_store(data_dir);
+
     // We create a loop here so the user can retry if an error happens.
     loop {
         let mut homeserver = String::new();
@@ -160,6 +552,7 @@ async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSession)
 
         match Client::builder()
             .homeserver_url(&homeserver)
+            .handle_refresh_tokens()
             // We use the SQLite store, which is enabled by default. This is the crucial part to
             // persist the encryption setup.
             // Note that other store backends are available and you can even implement your own.
@@ -193,168 +586,42 @@ async fn build_client(data_dir: &Path) -> anyhow::Result<(Client, ClientSession)
     }
 }
 
-async fn wait_for_confirmation(sas: SasVerification, emoji: [Emoji; 7]) {
-    println!("\nDo the emojis match: \n{}", format_emojis(emoji));
-    print!("Confirm with `yes` or cancel with `no`: ");
-    std::io::stdout()
-        .flush()
-        .expect("We should be able to flush stdout");
-
-    let mut input = String::new();
-    std::io::stdin()
-        .read_line(&mut input)
-        .expect("error: unable to read user input");
-
-    match input.trim().to_lowercase().as_ref() {
-        "yes" | "true" | "ok" => sas.confirm().await.unwrap(),
-        _ => sas.cancel().await.unwrap(),
-    }
-}
-
-async fn print_devices(user_id: &UserId, client: &Client) {
-    println!("Devices of user {user_id}");
-
-    for device in client
-        .encryption()
-        .get_user_devices(user_id)
-        .await
-        .unwrap()
-        .devices()
-    {
-        if device.device_id()
-            == client
-                .device_id()
-                .expect("We should be logged in now and know our device id")
-        {
-            continue;
-        }
-
-        println!(
-            "   {:<10} {:<30} {:<}",
-            device.device_id(),
-            device.display_name().unwrap_or("-"),
-            if device.is_verified() { "✅" } else { "❌" }
-        );
-    }
-}
-
-async fn sas_verification_handler(client: Client, sas: SasVerification) {
-    println!(
-        "Starting verification with {} {}",
-        &sas.other_device().user_id(),
-        &sas.other_device().device_id()
-    );
-    print_devices(sas.other_device().user_id(), &client).await;
-    sas.accept().await.unwrap();
-
-    let mut stream = sas.changes();
-
-    while let Some(state) = stream.next().await {
-        match state {
-            SasState::KeysExchanged {
-                emojis,
-                decimals: _,
-            } => {
-                tokio::spawn(wait_for_confirmation(
-                    sas.clone(),
-                    emojis
-                        .expect("We only support verifications using emojis")
-                        .emojis,
-                ));
-            }
-            SasState::Done { .. } => {
-                let device = sas.other_device();
-
-                println!(
-                    "Successfully verified device {} {} {:?}",
-                    device.user_id(),
-                    device.device_id(),
-                    device.local_trust_state()
-                );
-
-                print_devices(sas.other_device().user_id(), &client).await;
-
-                break;
-            }
-            SasState::Cancelled(cancel_info) => {
-                println!(
-                    "The verification has been cancelled, reason: {}",
-                    cancel_info.reason()
-                );
-
-                break;
-            }
-            SasState::Started { .. } | SasState::Accepted { .. } | SasState::Confirmed => (),
-        }
-    }
-}
-async fn request_verification_handler(client: Client, request: VerificationRequest) {
-    println!(
-        "Accepting verification request from {}",
-        request.other_user_id(),
+/// Answer the user-interactive authentication the homeserver asks for, with
+/// the password of the account, prompted for if not given.
+pub fn password_auth(
+    user_id: &UserId,
+    password: Option<String>,
+    uiaa_info: &UiaaInfo,
+) -> anyhow::Result<AuthData> {
+    let password = match password {
+        Some(password) => password,
+        None => prompt_secret("Password of the account: ")?,
+    };
+
+    let mut auth = Password::new(
+        UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+        password,
     );
-    request
-        .accept()
-        .await
-        .expect("Can't accept verification request");
-
-    let mut stream = request.changes();
-
-    while let Some(state) = stream.next().await {
-        match state {
-            VerificationRequestState::Created { .. }
-            | VerificationRequestState::Requested { .. }
-            | VerificationRequestState::Ready { .. } => (),
-            VerificationRequestState::Transitioned { verification } => {
-                // We only support SAS verification.
-                if let Verification::SasV1(s) = verification {
-                    tokio::spawn(sas_verification_handler(client, s));
-                    break;
-                }
-            }
-            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => break,
-        }
-    }
+    auth.session = uiaa_info.session.clone();
+    Ok(AuthData::Password(auth))
 }
 
-pub async fn setup_verification(client: &Client) {
-    println!("Setting up verification…");
-    // EMOJI VERIFICATION HERE
-    client.add_event_handler(
-        |ev: ToDeviceKeyVerificationRequestEvent, client: Client| async move {
-            let request = client
-                .encryption()
-                .get_verification_request(&ev.sender, &ev.content.transaction_id)
-                .await
-                .expect("Request object wasn't created");
-
-            tokio::spawn(request_verification_handler(client, request));
-        },
-    );
-
-    client.add_event_handler(
-        |ev: OriginalSyncRoomMessageEvent, client: Client| async move {
-            if let MessageType::VerificationRequest(_) = &ev.content.msgtype {
-                let request = client
-                    .encryption()
-                    .get_verification_request(&ev.sender, &ev.event_id)
-                    .await
-                    .expect("Request object wasn't created");
-
-                tokio::spawn(request_verification_handler(client, request));
-            }
-        },
-    );
+/// Read a secret from the terminal, without echoing it.
+///
+/// The secret is kept as typed, spaces included, only the newline is removed.
+pub fn prompt_secret(label: &str) -> anyhow::Result<String> {
+    rpassword::prompt_password(label)
+        .context("Unable to read from the terminal, set the secret in the environment instead")
 }
 
 /// The data needed to re-build a client.
 #[derive(Debug, Serialize, Deserialize)]
-struct ClientSession {
+pub struct ClientSession {
     /// The URL of the homeserver of the user.
-    homeserver: String,
+    pub homeserver: String,
 
     /// The path of the database.
-    db_path: PathBuf,
+    pub db_path: PathBuf,
 
     /// The passphrase of the database.
     passphrase: String,
@@ -362,18 +629,203 @@ struct ClientSession {
 
 /// The full session to persist.
 #[derive(Debug, Serialize, Deserialize)]
-struct FullSession {
+pub struct FullSession {
     /// The data to re-build the client.
-    client_session: ClientSession,
+    pub client_session: ClientSession,
 
     /// The Matrix user session.
-    user_session: MatrixSession,
+    pub user_session: MatrixSession,
 
-    /// The latest sync token.
+    /// The latest sync token of sessions from older versions.
     ///
-    /// It is only needed to persist it when using `Client::sync_once()` and we
-    /// want to make our syncs faster by not receiving all the initial sync
-    /// again.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    sync_token: Option<String>,
+    /// It is now stored in its own file so the session is only written on
+    /// login, see [`SyncTokenStore`](crate::sync_token::SyncTokenStore).
+    #[serde(default, skip_serializing)]
+    pub sync_token: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::{
+        config::SyncSettings,
+        ruma::{device_id, user_id},
+    };
+    use serde_json::json;
+    use std::time::Duration;
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::retry::Failure;
+
+    /// A homeserver that rejects the `expired` access token with a soft logout.
+    async fn mock_homeserver() -> MockServer {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "versions": ["v1.5"] })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .and(header("authorization", "Bearer expired"))
+            .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+                "errcode": "M_UNKNOWN_TOKEN",
+                "error": "The access token expired",
+                "soft_logout": true,
+            })))
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    /// A successful sync with the given access token.
+    fn mock_sync_with(access_token: &str) -> Mock {
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .and(header(
+                "authorization",
+                format!("Bearer {access_token}").as_str(),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "next_batch": "s1" })))
+    }
+
+    /// Persist a session on the mock homeserver with the `expired` access
+    /// token.
+    async fn persist_test_session(
+        dir: &Path,
+        server: &MockServer,
+        refresh_token: Option<&str>,
+    ) -> PathBuf {
+        let session_file = dir.join("session");
+        secrets::use_test_key(&session_file);
+
+        let session = FullSession {
+            client_session: ClientSession {
+                homeserver: server.uri(),
+                db_path: dir.join("store"),
+                passphrase: "passphrase".to_owned(),
+            },
+            user_session: MatrixSession {
+                meta: SessionMeta {
+                    user_id: user_id!("@bot:example.org").to_owned(),
+                    device_id: device_id!("BOTDEVICE").to_owned(),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: "expired".to_owned(),
+                    refresh_token: refresh_token.map(ToOwned::to_owned),
+                },
+            },
+            sync_token: None,
+        };
+        secrets::write(&session_file, &serde_json::to_string(&session).unwrap())
+            .await
+            .unwrap();
+
+        session_file
+    }
+
+    #[tokio::test]
+    async fn refreshed_tokens_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = mock_homeserver().await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/refresh"))
+            .and(body_partial_json(json!({ "refresh_token": "refresh" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "refreshed",
+                "refresh_token": "rotated",
+                "expires_in_ms": 60_000,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        mock_sync_with("refreshed").expect(1).mount(&server).await;
+
+        let session_file = persist_test_session(dir.path(), &server, Some("refresh")).await;
+        let (client, _) = restore_session(&session_file).await.unwrap();
+        let session_changes = tokio::spawn(persist_session_changes(
+            client.clone(),
+            session_file.clone(),
+        ));
+
+        // The client refreshes the token on its own and retries the sync.
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        // The session file is updated in the background.
+        let mut tokens = None;
+        for _ in 0..50 {
+            let session = load_session(&session_file).await.unwrap();
+            if session.user_session.tokens.access_token == "refreshed" {
+                tokens = Some(session.user_session.tokens);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        session_changes.abort();
+
+        let tokens = tokens.expect("The refreshed tokens weren't persisted");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("rotated"));
+    }
+
+    #[tokio::test]
+    async fn soft_logout_logs_in_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = mock_homeserver().await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v3/login"))
+            .and(body_partial_json(json!({
+                "type": "m.login.password",
+                "password": "secret",
+                "device_id": "BOTDEVICE",
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@bot:example.org",
+                "device_id": "BOTDEVICE",
+                "access_token": "relogged",
+                "refresh_token": "refresh",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        mock_sync_with("relogged").expect(1).mount(&server).await;
+
+        let session_file = persist_test_session(dir.path(), &server, None).await;
+        let (client, _) = restore_session(&session_file).await.unwrap();
+
+        let error = client.sync_once(SyncSettings::default()).await.unwrap_err();
+        assert_eq!(
+            Failure::of(&error),
+            Failure::LoggedOut { soft_logout: true }
+        );
+
+        let credentials = Credentials {
+            homeserver: server.uri(),
+            method: LoginMethod::Password {
+                username: "@bot:example.org".to_owned(),
+                password: "secret".to_owned(),
+            },
+        };
+        let reauthenticated = reauthenticate(&client, &session_file, Some(credentials), true)
+            .await
+            .unwrap();
+        assert_eq!(reauthenticated, Reauthenticated::LoggedIn);
+
+        // A new client syncs with the new token, on the same store.
+        drop(client);
+        let (client, _) = restore_session(&session_file).await.unwrap();
+        client.sync_once(SyncSettings::default()).await.unwrap();
+
+        let tokens = load_session(&session_file)
+            .await
+            .unwrap()
+            .user_session
+            .tokens;
+        assert_eq!(tokens.access_token, "relogged");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("refresh"));
+    }
 }
@@ -0,0 +1,172 @@
+use log::info;
+use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use crate::config::CatchUpConfig;
+
+/// Bounds the messages handled while catching up with the ones sent while the
+/// bot was offline.
+#[derive(Debug)]
+pub struct CatchUp {
+    active: AtomicBool,
+    handled: AtomicUsize,
+    skipped: AtomicUsize,
+
+    /// Whether a message was handled since [`CatchUp::take_answered`] was
+    /// last called.
+    answered: AtomicBool,
+
+    max_age: Duration,
+    max_count: usize,
+}
+
+impl CatchUp {
+    pub fn new(config: &CatchUpConfig) -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            handled: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            answered: AtomicBool::new(false),
+            max_age: Duration::from_secs(config.max_age_secs),
+            max_count: config.max_count,
+        }
+    }
+
+    /// Start counting the messages as missed ones.
+    pub fn start(&self) {
+        self.active.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop catching up, every message is handled from now on.
+    pub fn finish(&self) {
+        if self.active.swap(false, Ordering::SeqCst) {
+            info!(
+                "Caught up with {} missed messages, skipped {}",
+                self.handled.load(Ordering::SeqCst),
+                self.skipped.load(Ordering::SeqCst)
+            );
+        }
+    }
+
+    /// Whether a message the bot would answer, sent at `origin_server_ts`,
+    /// should be handled.
+    pub fn should_handle(&self, origin_server_ts: MilliSecondsSinceUnixEpoch) -> bool {
+        let handle = !self.active.load(Ordering::SeqCst) || self.within_bounds(origin_server_ts);
+        if handle {
+            self.answered.store(true, Ordering::SeqCst);
+        } else {
+            self.skipped.fetch_add(1, Ordering::SeqCst);
+        }
+        handle
+    }
+
+    /// Whether a message was handled since the last call, in which case the
+    /// sync token must be persisted so it isn't handled again after a crash.
+    pub fn take_answered(&self) -> bool {
+        self.answered.swap(false, Ordering::SeqCst)
+    }
+
+    fn within_bounds(&self, origin_server_ts: MilliSecondsSinceUnixEpoch) -> bool {
+        let age = origin_server_ts
+            .to_system_time()
+            .and_then(|sent_at| SystemTime::now().duration_since(sent_at).ok())
+            .unwrap_or_default();
+        let within_count = || {
+            self.handled
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |handled| {
+                    (handled < self.max_count).then_some(handled + 1)
+                })
+                .is_ok()
+        };
+
+        age <= self.max_age && within_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catch_up(max_age_secs: u64, max_count: usize) -> CatchUp {
+        CatchUp::new(&CatchUpConfig {
+            enabled: true,
+            max_age_secs,
+            max_count,
+        })
+    }
+
+    fn sent_ago(age: Duration) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch::from_system_time(SystemTime::now() - age).unwrap()
+    }
+
+    #[test]
+    fn max_age() {
+        let catch_up = catch_up(60, 10);
+        catch_up.start();
+
+        assert!(catch_up.should_handle(sent_ago(Duration::from_secs(30))));
+        assert!(!catch_up.should_handle(sent_ago(Duration::from_secs(90))));
+        // Sent "in the future" by a server with a skewed clock.
+        assert!(catch_up.should_handle(
+            MilliSecondsSinceUnixEpoch::from_system_time(
+                SystemTime::now() + Duration::from_secs(30)
+            )
+            .unwrap()
+        ));
+    }
+
+    #[test]
+    fn max_count() {
+        let catch_up = catch_up(60, 2);
+        catch_up.start();
+
+        let recent = sent_ago(Duration::from_secs(1));
+        assert!(catch_up.should_handle(recent));
+        // Skipped messages don't count.
+        assert!(!catch_up.should_handle(sent_ago(Duration::from_secs(90))));
+        assert!(catch_up.should_handle(recent));
+        assert!(!catch_up.should_handle(recent));
+        assert_eq!(catch_up.handled.load(Ordering::SeqCst), 2);
+        assert_eq!(catch_up.skipped.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn only_the_missed_messages_are_bounded() {
+        let catch_up = catch_up(60, 0);
+        let old = sent_ago(Duration::from_secs(90));
+
+        // Before the sync with the saved token, nothing is bounded.
+        assert!(catch_up.should_handle(old));
+
+        catch_up.start();
+        assert!(!catch_up.should_handle(old));
+
+        // After it, the messages are new.
+        catch_up.finish();
+        assert!(catch_up.should_handle(old));
+        assert_eq!(catch_up.handled.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn take_answered() {
+        let catch_up = catch_up(60, 1);
+        assert!(!catch_up.take_answered());
+
+        catch_up.start();
+        let recent = sent_ago(Duration::from_secs(1));
+        assert!(catch_up.should_handle(recent));
+        assert!(catch_up.take_answered());
+        assert!(!catch_up.take_answered());
+
+        // A skipped message isn't answered.
+        assert!(!catch_up.should_handle(recent));
+        assert!(!catch_up.take_answered());
+
+        catch_up.finish();
+        assert!(catch_up.should_handle(recent));
+        assert!(catch_up.take_answered());
+    }
+}
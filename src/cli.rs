@@ -0,0 +1,509 @@
+use anyhow::{bail, Context};
+use clap::{Args, Parser, Subcommand};
+use matrix_sdk::ruma::UserId;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    auth::{Credentials, LoginMethod},
+    CLIENT_NAME,
+};
+
+/// A Matrix bot.
+#[derive(Debug, Parser)]
+#[command(name = "oxybot", version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Directory holding the session, store and config.
+    /// Defaults to `oxybot` in the platform data directory.
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Name of the profile to use, each profile has its own data directory.
+    ///
+    /// `run` can be given several profiles, to run their accounts together.
+    #[arg(long, global = true)]
+    pub profile: Vec<String>,
+
+    /// Run every profile that is logged in.
+    #[arg(long, global = true, conflicts_with = "profile")]
+    pub all_profiles: bool,
+
+    /// Path of the config file. Defaults to `config.toml` in the data directory.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Arguments of `run`, when no subcommand is given.
+    #[command(flatten)]
+    pub run: LoginArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Log in with a new device and persist the session.
+    Login(LoginCommandArgs),
+
+    /// Restore the session and run the bot. This is the default.
+    ///
+    /// If there is no session yet and credentials are given, log in first.
+    Run(LoginArgs),
+
+    /// Log out, invalidating the device, and delete the session and store.
+    Logout,
+
+    /// Print the user and device of the persisted session.
+    Whoami,
+
+    /// Print the state of the data directory, session and config.
+    Status,
+
+    /// List the profiles and whether they are logged in.
+    Profiles,
+
+    /// Verify a user or one of their devices, confirming on the terminal.
+    ///
+    /// Without a user, verify the bot's own other sessions. The bot must not
+    /// be running with the same profile.
+    Verify(VerifyArgs),
+
+    /// Set up or restore cross-signing and the key backup of the account.
+    #[command(subcommand)]
+    Encryption(EncryptionCommand),
+
+    /// Export or import the room keys, in the format of the other clients.
+    #[command(subcommand)]
+    Keys(KeysCommand),
+
+    /// List the devices of users, and manage their trust and the bot's own.
+    #[command(subcommand)]
+    Devices(DevicesCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DevicesCommand {
+    /// List the devices of a user, the bot's own by default.
+    List(ListDevicesArgs),
+
+    /// Trust a device as if it was verified.
+    Trust(DeviceArgs),
+
+    /// Blacklist a device, so it doesn't receive the room keys of the bot.
+    Blacklist(DeviceArgs),
+
+    /// Forget the trust set with `trust` or `blacklist`.
+    Untrust(DeviceArgs),
+
+    /// Delete devices of the bot's account.
+    Delete(DeleteDevicesArgs),
+}
+
+/// Arguments of `devices list`.
+#[derive(Debug, Args)]
+pub struct ListDevicesArgs {
+    /// The user whose devices to list.
+    pub user_id: Option<String>,
+}
+
+/// Arguments of the commands about a single device.
+#[derive(Debug, Args)]
+pub struct DeviceArgs {
+    /// The user who owns the device.
+    pub user_id: String,
+
+    /// The device.
+    pub device_id: String,
+}
+
+/// Arguments of `devices delete`.
+#[derive(Debug, Args)]
+pub struct DeleteDevicesArgs {
+    /// The devices to delete.
+    #[arg(required_unless_present = "stale_days")]
+    pub device_ids: Vec<String>,
+
+    /// Delete the other devices the bot logged in, that weren't seen for this
+    /// many days.
+    #[arg(long, conflicts_with = "device_ids")]
+    pub stale_days: Option<u64>,
+
+    /// Only print the devices that would be deleted.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// File containing the password of the account, which the homeserver can
+    /// ask for to delete devices. Prompted for otherwise.
+    #[arg(long, env = "OXYBOT_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+
+    /// The password of the account.
+    #[arg(skip = std::env::var("OXYBOT_PASSWORD").ok())]
+    pub password: Option<String>,
+}
+
+impl DeleteDevicesArgs {
+    pub fn password(&self) -> anyhow::Result<Option<String>> {
+        read_secret(self.password.as_deref(), self.password_file.as_deref())
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EncryptionCommand {
+    /// Set up cross-signing and a backup of the room keys, and print the
+    /// recovery key.
+    Bootstrap(BootstrapArgs),
+
+    /// Restore cross-signing and the key backup on this device, from the
+    /// recovery key.
+    Recover(RecoverArgs),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeysCommand {
+    /// Export the room keys of the store to a file encrypted with a
+    /// passphrase.
+    Export(KeysFileArgs),
+
+    /// Import the room keys of a file exported by the bot or another client.
+    Import(KeysFileArgs),
+}
+
+/// Arguments of `keys export` and `keys import`.
+#[derive(Debug, Args)]
+pub struct KeysFileArgs {
+    /// The file of the exported keys.
+    pub file: PathBuf,
+
+    /// File containing the passphrase of the exported keys. Prompted for
+    /// otherwise.
+    #[arg(long, env = "OXYBOT_KEYS_PASSPHRASE_FILE")]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// The passphrase of the exported keys.
+    #[arg(skip = std::env::var("OXYBOT_KEYS_PASSPHRASE").ok())]
+    pub passphrase: Option<String>,
+}
+
+impl KeysFileArgs {
+    pub fn passphrase(&self) -> anyhow::Result<Option<String>> {
+        read_secret(self.passphrase.as_deref(), self.passphrase_file.as_deref())
+    }
+}
+
+/// Arguments of `encryption bootstrap`.
+#[derive(Debug, Args)]
+pub struct BootstrapArgs {
+    /// File containing the password of the account, which the homeserver can
+    /// ask for to upload the cross-signing keys. Prompted for otherwise.
+    #[arg(long, env = "OXYBOT_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+
+    /// The password of the account.
+    #[arg(skip = std::env::var("OXYBOT_PASSWORD").ok())]
+    pub password: Option<String>,
+}
+
+impl BootstrapArgs {
+    pub fn password(&self) -> anyhow::Result<Option<String>> {
+        read_secret(self.password.as_deref(), self.password_file.as_deref())
+    }
+}
+
+/// Arguments of `encryption recover`.
+#[derive(Debug, Args)]
+pub struct RecoverArgs {
+    /// File containing the recovery key. Prompted for otherwise.
+    #[arg(long, env = "OXYBOT_RECOVERY_KEY_FILE")]
+    pub recovery_key_file: Option<PathBuf>,
+
+    /// The recovery key.
+    #[arg(skip = std::env::var("OXYBOT_RECOVERY_KEY").ok())]
+    pub recovery_key: Option<String>,
+}
+
+impl RecoverArgs {
+    pub fn recovery_key(&self) -> anyhow::Result<Option<String>> {
+        read_secret(
+            self.recovery_key.as_deref(),
+            self.recovery_key_file.as_deref(),
+        )
+    }
+}
+
+/// Arguments of `verify`.
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// The user to verify. Defaults to the bot's own user.
+    pub user_id: Option<String>,
+
+    /// The device to verify. Without it, the request goes to every device of
+    /// the user, which needs cross-signing.
+    pub device_id: Option<String>,
+}
+
+/// Arguments of `login`.
+#[derive(Debug, Args)]
+pub struct LoginCommandArgs {
+    /// Log in in a browser, with the single sign-on of the homeserver.
+    ///
+    /// Only the homeserver is used from the other arguments.
+    #[arg(long)]
+    pub sso: bool,
+
+    #[command(flatten)]
+    pub login: LoginArgs,
+}
+
+/// Credentials for a login without prompts, for example under systemd or in a
+/// container.
+///
+/// Secrets can only be passed through the environment or files, so they don't
+/// show up in the process list.
+#[derive(Debug, Args)]
+pub struct LoginArgs {
+    /// URL of the homeserver.
+    #[arg(long, env = "OXYBOT_HOMESERVER")]
+    pub homeserver: Option<String>,
+
+    /// Username, or full user ID when logging in with an access token.
+    #[arg(long, env = "OXYBOT_USERNAME")]
+    pub username: Option<String>,
+
+    /// File containing the password.
+    #[arg(long, env = "OXYBOT_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+
+    /// The password.
+    #[arg(skip = std::env::var("OXYBOT_PASSWORD").ok())]
+    pub password: Option<String>,
+
+    /// File containing a pre-issued access token, used instead of a password.
+    #[arg(long, env = "OXYBOT_ACCESS_TOKEN_FILE")]
+    pub access_token_file: Option<PathBuf>,
+
+    /// A pre-issued access token, used instead of a password.
+    #[arg(skip = std::env::var("OXYBOT_ACCESS_TOKEN").ok())]
+    pub access_token: Option<String>,
+
+    /// Device ID the access token was issued for.
+    #[arg(long, env = "OXYBOT_DEVICE_ID")]
+    pub device_id: Option<String>,
+
+    /// Fail instead of prompting when no credentials are given.
+    #[arg(long)]
+    pub non_interactive: bool,
+}
+
+impl LoginArgs {
+    /// The credentials to log in without prompting, if any were given.
+    pub fn credentials(&self) -> anyhow::Result<Option<Credentials>> {
+        self.credentials_or(None)
+    }
+
+    /// The credentials to log an existing session in again, if a password or
+    /// access token was given.
+    ///
+    /// The homeserver and username default to the ones of the session.
+    pub fn credentials_for_session(
+        &self,
+        homeserver: &str,
+        user_id: &UserId,
+    ) -> anyhow::Result<Option<Credentials>> {
+        self.credentials_or(Some((homeserver, user_id)))
+    }
+
+    fn credentials_or(
+        &self,
+        session: Option<(&str, &UserId)>,
+    ) -> anyhow::Result<Option<Credentials>> {
+        let password = read_secret(self.password.as_deref(), self.password_file.as_deref())?;
+        let access_token = read_secret(
+            self.access_token.as_deref(),
+            self.access_token_file.as_deref(),
+        )?;
+        if session.is_some() && password.is_none() && access_token.is_none() {
+            return Ok(None);
+        }
+
+        let homeserver = self
+            .homeserver
+            .clone()
+            .or_else(|| session.map(|(homeserver, _)| homeserver.to_owned()));
+        let Some(homeserver) = homeserver else {
+            if self.non_interactive
+                || self.username.is_some()
+                || password.is_some()
+                || access_token.is_some()
+            {
+                bail!("Missing homeserver, set --homeserver or OXYBOT_HOMESERVER");
+            }
+            return Ok(None);
+        };
+
+        let username = self
+            .username
+            .clone()
+            .or_else(|| session.map(|(_, user_id)| user_id.to_string()));
+        let Some(username) = username else {
+            bail!("Missing username, set --username or OXYBOT_USERNAME");
+        };
+
+        let method = match (access_token, password) {
+            (Some(_), Some(_)) => {
+                bail!("Both a password and an access token are set, only one can be used")
+            }
+            (Some(access_token), None) => {
+                let Some(device_id) = self.device_id.as_deref() else {
+                    bail!("Missing device ID for the access token, set --device-id or OXYBOT_DEVICE_ID");
+                };
+                LoginMethod::AccessToken {
+                    user_id: username.as_str().try_into().with_context(|| {
+                        format!("The username must be a full user ID with an access token, got {username:?}")
+                    })?,
+                    device_id: device_id.into(),
+                    access_token,
+                }
+            }
+            (None, Some(password)) => LoginMethod::Password { username, password },
+            (None, None) => bail!(
+                "Missing password, set OXYBOT_PASSWORD, --password-file or OXYBOT_PASSWORD_FILE"
+            ),
+        };
+
+        Ok(Some(Credentials { homeserver, method }))
+    }
+}
+
+/// Get a secret from its value or from the file containing it.
+fn read_secret(value: Option<&str>, file: Option<&Path>) -> anyhow::Result<Option<String>> {
+    if let Some(file) = file {
+        let secret = std::fs::read_to_string(file)
+            .with_context(|| format!("Unable to read '{}'", file.to_string_lossy()))?;
+        return Ok(Some(secret.trim_end_matches(['\r', '\n']).to_owned()));
+    }
+    Ok(value.map(ToOwned::to_owned))
+}
+
+impl Cli {
+    /// The profile to use, for the commands that work with a single one.
+    pub fn profile(&self) -> anyhow::Result<Profile> {
+        if self.all_profiles || self.profile.len() > 1 {
+            bail!("Only `run` can use several profiles");
+        }
+        Ok(self.profiles()?.remove(0))
+    }
+
+    /// The profiles to use, taking `--data-dir`, `--profile` and
+    /// `--all-profiles` into account.
+    pub fn profiles(&self) -> anyhow::Result<Vec<Profile>> {
+        let base = self.base_data_dir()?;
+
+        if self.all_profiles {
+            let profiles: Vec<_> = list_profiles(&base)?
+                .into_iter()
+                .filter(|profile| profile.session_file().exists())
+                .collect();
+            if profiles.is_empty() {
+                bail!("No profile is logged in");
+            }
+            return Ok(profiles);
+        }
+
+        if self.profile.is_empty() {
+            return Ok(vec![Profile {
+                name: None,
+                data_dir: base,
+            }]);
+        }
+
+        let mut profiles: Vec<Profile> = Vec::new();
+        for name in &self.profile {
+            if !is_valid_profile_name(name) {
+                bail!("Invalid profile name {name:?}");
+            }
+            if profiles
+                .iter()
+                .any(|profile| profile.name.as_ref() == Some(name))
+            {
+                bail!("The profile {name:?} is given twice");
+            }
+            profiles.push(Profile {
+                name: Some(name.clone()),
+                data_dir: profiles_dir(&base).join(name),
+            });
+        }
+        Ok(profiles)
+    }
+
+    /// The data directory of the default profile, which holds the other
+    /// profiles.
+    pub fn base_data_dir(&self) -> anyhow::Result<PathBuf> {
+        Ok(match &self.data_dir {
+            Some(data_dir) => data_dir.clone(),
+            // Stores in ~/Library/Application Support/oxybot
+            None => dirs::data_dir()
+                .ok_or_else(|| anyhow::anyhow!("No data directory found, use --data-dir"))?
+                .join(CLIENT_NAME),
+        })
+    }
+}
+
+/// An account, with its own session, store and config in its data directory.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// The name of the profile, `None` for the default one.
+    pub name: Option<String>,
+
+    pub data_dir: PathBuf,
+}
+
+impl Profile {
+    /// The file where the session is persisted.
+    pub fn session_file(&self) -> PathBuf {
+        self.data_dir.join("session")
+    }
+
+    /// The name to show for the profile.
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+}
+
+/// The profiles in the data directory: the default one, then the named ones
+/// sorted by name.
+pub fn list_profiles(base: &Path) -> anyhow::Result<Vec<Profile>> {
+    let mut profiles = vec![Profile {
+        name: None,
+        data_dir: base.to_owned(),
+    }];
+
+    let dir = profiles_dir(base);
+    if !dir.exists() {
+        return Ok(profiles);
+    }
+
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+            continue;
+        };
+        if entry.file_type()?.is_dir() && is_valid_profile_name(&name) {
+            profiles.push(Profile {
+                name: Some(name),
+                data_dir: entry.path(),
+            });
+        }
+    }
+    // The default profile has no name, so it stays first.
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(profiles)
+}
+
+fn profiles_dir(base: &Path) -> PathBuf {
+    base.join("profiles")
+}
+
+fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(std::path::is_separator)
+}
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, fmt, str::Chars};
+
+/// The parsed arguments of a command.
+///
+/// Words are separated by whitespace, and can be grouped with single or double
+/// quotes that start a word. A quote inside a word, or one that is never
+/// closed, is kept as is. A backslash escapes the next character. Unquoted
+/// words starting with `--` are flags: `--name` is a boolean flag and
+/// `--name=value` an option. A lone `--` ends the flags, everything after it is
+/// positional.
+#[derive(Debug, Default, Clone)]
+pub struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, Option<String>>,
+}
+
+/// An error while splitting the arguments of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgsError {
+    /// The input ends with a backslash.
+    TrailingBackslash,
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TrailingBackslash => write!(f, "trailing backslash"),
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+/// A word of the input, and whether any part of it was quoted or escaped.
+struct Word {
+    text: String,
+    literal: bool,
+}
+
+impl Args {
+    /// Split the name of the command from its arguments.
+    ///
+    /// Returns `None` as the name if the input is empty.
+    pub fn parse_command(input: &str) -> Result<(Option<String>, Self), ArgsError> {
+        let mut words = split(input)?.into_iter();
+        let name = words.next().map(|word| word.text);
+        Ok((name, Self::from_words(words)))
+    }
+
+    fn from_words(words: impl IntoIterator<Item = Word>) -> Self {
+        let mut args = Self::default();
+        let mut flags_done = false;
+
+        for word in words {
+            if flags_done || word.literal {
+                args.positional.push(word.text);
+                continue;
+            }
+
+            match word.text.strip_prefix("--") {
+                Some("") => flags_done = true,
+                Some(flag) => {
+                    let (name, value) = match flag.split_once('=') {
+                        Some((name, value)) => (name, Some(value.to_owned())),
+                        None => (flag, None),
+                    };
+                    args.flags.insert(name.to_owned(), value);
+                }
+                None => args.positional.push(word.text),
+            }
+        }
+
+        args
+    }
+
+    /// The positional argument at `index`.
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.positional.get(index).map(String::as_str)
+    }
+
+    /// The positional arguments from `index`, joined with spaces.
+    pub fn rest(&self, index: usize) -> Option<String> {
+        let rest = self.positional.get(index..)?;
+        (!rest.is_empty()).then(|| rest.join(" "))
+    }
+}
+
+/// Split the input into words, handling quotes and escapes.
+fn split(input: &str) -> Result<Vec<Word>, ArgsError> {
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                words.extend(current.take());
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or(ArgsError::TrailingBackslash)?;
+                let word = current.get_or_insert_with(Word::empty);
+                word.text.push(escaped);
+                word.literal = true;
+            }
+            '"' | '\'' if current.is_none() => match quoted(chars.clone(), c) {
+                Some((text, after)) => {
+                    current = Some(Word {
+                        text,
+                        literal: true,
+                    });
+                    chars = after;
+                }
+                None => current = Some(Word::from(c)),
+            },
+            c => current.get_or_insert_with(Word::empty).text.push(c),
+        }
+    }
+    words.extend(current);
+
+    Ok(words)
+}
+
+/// The text up to the closing `quote`, and the input after it.
+///
+/// Returns `None` if the quote is never closed.
+fn quoted(mut chars: Chars<'_>, quote: char) -> Option<(String, Chars<'_>)> {
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            q if q == quote => return Some((text, chars)),
+            // Escapes only make sense in double quotes.
+            '\\' if quote == '"' => text.push(chars.next()?),
+            other => text.push(other),
+        }
+    }
+}
+
+impl Word {
+    fn empty() -> Self {
+        Self {
+            text: String::new(),
+            literal: false,
+        }
+    }
+}
+
+impl From<char> for Word {
+    fn from(c: char) -> Self {
+        Self {
+            text: c.into(),
+            literal: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> (Option<String>, Args) {
+        Args::parse_command(input).unwrap()
+    }
+
+    #[test]
+    fn empty_input() {
+        let (name, args) = parse("   ");
+        assert_eq!(name, None);
+        assert_eq!(args.get(0), None);
+        assert_eq!(args.rest(0), None);
+    }
+
+    #[test]
+    fn name_and_positional() {
+        let (name, args) = parse(" quote  add   Know thyself. ");
+        assert_eq!(name.as_deref(), Some("quote"));
+        assert_eq!(args.get(0), Some("add"));
+        assert_eq!(args.rest(1).as_deref(), Some("Know thyself."));
+        assert_eq!(args.rest(3), None);
+    }
+
+    #[test]
+    fn quotes_and_escapes() {
+        let (_, args) = parse(r#"cmd "a b" 'c "d"' e\ f "g \"h\"" 'i\j'"#);
+        assert_eq!(args.get(0), Some("a b"));
+        assert_eq!(args.get(1), Some(r#"c "d""#));
+        assert_eq!(args.get(2), Some("e f"));
+        assert_eq!(args.get(3), Some(r#"g "h""#));
+        assert_eq!(args.get(4), Some(r"i\j"));
+    }
+
+    #[test]
+    fn flags_and_options() {
+        let (_, args) = parse("cmd --dry-run --tags=a,b word --empty= -- --not-a-flag");
+        assert_eq!(args.flags.get("dry-run"), Some(&None));
+        assert_eq!(args.flags.get("tags"), Some(&Some("a,b".to_owned())));
+        assert_eq!(args.flags.get("empty"), Some(&Some(String::new())));
+        assert!(!args.flags.contains_key("not-a-flag"));
+        assert_eq!(args.get(0), Some("word"));
+        assert_eq!(args.get(1), Some("--not-a-flag"));
+    }
+
+    #[test]
+    fn quoted_flags_are_positional() {
+        let (_, args) = parse(r#"cmd "--tags=a" \--b"#);
+        assert!(args.flags.is_empty());
+        assert_eq!(args.get(0), Some("--tags=a"));
+        assert_eq!(args.get(1), Some("--b"));
+    }
+
+    #[test]
+    fn quotes_inside_words_are_kept() {
+        let (_, args) = parse(r#"cmd Don't panic, it's "fine" rock'n'roll"#);
+        assert_eq!(
+            args.rest(0).as_deref(),
+            Some("Don't panic, it's fine rock'n'roll")
+        );
+        assert_eq!(args.get(0), Some("Don't"));
+    }
+
+    #[test]
+    fn unterminated_quotes_are_kept() {
+        let (_, args) = parse(r#"cmd "open quote 'single"#);
+        assert_eq!(args.get(0), Some("\"open"));
+        assert_eq!(args.get(1), Some("quote"));
+        assert_eq!(args.get(2), Some("'single"));
+
+        let (_, args) = parse(r#"cmd 'a b' "c \"d"#);
+        assert_eq!(args.get(0), Some("a b"));
+        assert_eq!(args.get(1), Some("\"c"));
+        assert_eq!(args.get(2), Some("\"d"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Args::parse_command(r"cmd trailing\").unwrap_err(),
+            ArgsError::TrailingBackslash
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use futures_util::future::BoxFuture;
+use matrix_sdk::ruma::{OwnedRoomId, UserId};
+
+use super::{Command, CommandContext};
+use crate::{devices, error::BotResult};
+
+/// List the devices of a user and whether the bot trusts them.
+pub struct DevicesCommand {
+    /// The only room where the bot's own devices can be listed.
+    admin_room: Option<OwnedRoomId>,
+}
+
+impl DevicesCommand {
+    pub fn new(admin_room: Option<OwnedRoomId>) -> Self {
+        Self { admin_room }
+    }
+}
+
+impl Command for DevicesCommand {
+    fn name(&self) -> &'static str {
+        "devices"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[user]"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the devices of a user, yours by default"
+    }
+
+    fn run<'a>(&'a self, ctx: CommandContext<'a>) -> BoxFuture<'a, BotResult> {
+        Box::pin(async move {
+            let user_id = match ctx.args.get(0) {
+                Some(user_id) => match UserId::parse(user_id) {
+                    Ok(user_id) => user_id,
+                    Err(error) => {
+                        return ctx
+                            .reply(format!("`{user_id}` is not a user ID: {error}"))
+                            .await
+                    }
+                },
+                None => ctx.sender.to_owned(),
+            };
+
+            // They tell when the bot was last active.
+            let client = ctx.room.client();
+            let in_admin_room = self.admin_room.as_deref() == Some(ctx.room.room_id());
+            if client.user_id() == Some(&*user_id) && !in_admin_room {
+                return ctx
+                    .reply("The devices of the bot can only be listed in the admin room")
+                    .await;
+            }
+
+            let rows = devices::list(&client, &user_id).await?;
+            if rows.is_empty() {
+                return ctx.reply(format!("No known devices for {user_id}")).await;
+            }
+
+            ctx.reply_html(
+                format!("Devices of {user_id}:\n{}", devices::format_table(&rows)),
+                format!(
+                    "<p>Devices of {}:</p>\n{}",
+                    devices::escape_html(user_id.as_str()),
+                    devices::format_html_table(&rows)
+                ),
+            )
+            .await
+        })
+    }
+}
@@ -0,0 +1,76 @@
+use futures_util::future::BoxFuture;
+use std::{fmt::Write, sync::Arc, time::Duration};
+
+use super::{Command, CommandContext};
+use crate::{
+    error::BotResult,
+    health::{Health, HealthState},
+};
+
+/// Tell how the connection to the homeserver is doing.
+pub struct HealthCommand {
+    health: Arc<Health>,
+}
+
+impl HealthCommand {
+    pub fn new(health: Arc<Health>) -> Self {
+        Self { health }
+    }
+}
+
+impl Command for HealthCommand {
+    fn name(&self) -> &'static str {
+        "health"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show how the bot is doing"
+    }
+
+    fn run<'a>(&'a self, ctx: CommandContext<'a>) -> BoxFuture<'a, BotResult> {
+        Box::pin(async move {
+            let report = self.health.report();
+
+            let mut reply = format!(
+                "{} for {}",
+                capitalize(&report.state.to_string()),
+                format_duration(report.since.elapsed())
+            );
+            if let Some(last_sync) = report.last_sync {
+                let _ = write!(
+                    reply,
+                    ", last sync {} ago",
+                    format_duration(last_sync.elapsed())
+                );
+            }
+            if report.state == HealthState::Degraded {
+                let _ = write!(
+                    reply,
+                    ", {} failed syncs in a row",
+                    report.consecutive_failures
+                );
+            }
+
+            ctx.reply(reply).await
+        })
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+/// Format a duration in its largest whole unit, like `3m` or `2h`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
@@ -0,0 +1,34 @@
+use futures_util::future::BoxFuture;
+
+use super::{Command, CommandContext};
+use crate::error::{BotError, BotResult};
+
+/// Greet the room.
+pub struct Hello;
+
+impl Command for Hello {
+    fn name(&self) -> &'static str {
+        "hello"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["hi"]
+    }
+
+    fn description(&self) -> &'static str {
+        "Say hello"
+    }
+
+    fn run<'a>(&'a self, ctx: CommandContext<'a>) -> BoxFuture<'a, BotResult> {
+        Box::pin(async move {
+            let client = ctx.room.client();
+            let user_id = client.user_id().ok_or(BotError::NotLoggedIn)?;
+
+            let user = client.get_profile(user_id).await?;
+            let display_name = user.displayname.unwrap_or("Stranger".to_string());
+
+            ctx.reply("Well hello there ".to_string() + &display_name)
+                .await
+        })
+    }
+}
@@ -0,0 +1,239 @@
+use futures_util::future::BoxFuture;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, UserId},
+    Room,
+};
+use std::fmt::Write;
+
+use crate::error::BotResult;
+
+mod args;
+mod devices;
+mod health;
+mod hello;
+mod quote;
+mod verification;
+
+pub use args::Args;
+pub use devices::DevicesCommand;
+pub use health::HealthCommand;
+pub use hello::Hello;
+pub use quote::QuoteCommand;
+pub use verification::VerificationCommand;
+
+/// What a command handler gets to work with.
+pub struct CommandContext<'a> {
+    /// The room the command was sent in.
+    pub room: &'a Room,
+
+    /// The user who sent the command.
+    pub sender: &'a UserId,
+
+    /// The parsed arguments, without the prefix and command name.
+    pub args: Args,
+
+    /// The arguments as they were sent, for commands that take free text.
+    pub raw_args: &'a str,
+}
+
+impl CommandContext<'_> {
+    /// Reply to the command with a plain text message.
+    pub async fn reply(&self, body: impl Into<String>) -> BotResult {
+        let message = RoomMessageEventContent::text_plain(body.into());
+        self.room.send(message).await?;
+        Ok(())
+    }
+
+    /// Reply to the command with an HTML message, and its plain text version
+    /// for the clients that don't render HTML.
+    pub async fn reply_html(&self, plain: impl Into<String>, html: impl Into<String>) -> BotResult {
+        let message = RoomMessageEventContent::text_html(plain.into(), html.into());
+        self.room.send(message).await?;
+        Ok(())
+    }
+}
+
+/// A command the bot answers to, like `!oxy hello`.
+pub trait Command: Send + Sync {
+    /// The name used to call the command.
+    fn name(&self) -> &'static str;
+
+    /// Other names the command can be called with.
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The arguments of the command, shown in the help.
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    /// A one-line description, shown in the help.
+    fn description(&self) -> &'static str;
+
+    /// Handle a call to the command.
+    fn run<'a>(&'a self, ctx: CommandContext<'a>) -> BoxFuture<'a, BotResult>;
+}
+
+/// The registry of commands, which parses messages and dispatches them to the
+/// right command.
+///
+/// `help` is always available and lists the registered commands.
+pub struct CommandRouter {
+    prefix: String,
+    commands: Vec<Box<dyn Command>>,
+    default_command: Option<&'static str>,
+}
+
+impl CommandRouter {
+    /// Create a router for messages starting with `prefix`.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            commands: Vec::new(),
+            default_command: None,
+        }
+    }
+
+    /// Register a command.
+    ///
+    /// Panics if the name or an alias is already taken, since that's a
+    /// programming error.
+    pub fn register(mut self, command: impl Command + 'static) -> Self {
+        for name in std::iter::once(command.name()).chain(command.aliases().iter().copied()) {
+            assert!(
+                name != "help" && self.find(name).is_none(),
+                "Command name `{name}` is registered twice"
+            );
+        }
+        self.commands.push(Box::new(command));
+        self
+    }
+
+    /// Set the command to run when the prefix is sent alone.
+    pub fn default_command(mut self, name: &'static str) -> Self {
+        self.default_command = Some(name);
+        self
+    }
+
+    fn find(&self, name: &str) -> Option<&dyn Command> {
+        self.commands
+            .iter()
+            .find(|command| command.name() == name || command.aliases().contains(&name))
+            .map(Box::as_ref)
+    }
+
+    /// Handle a message, if it is a command.
+    ///
+    /// Returns whether the message was meant for the bot.
+    pub async fn handle(&self, room: &Room, sender: &UserId, body: &str) -> BotResult<bool> {
+        let Some(rest) = self.strip_prefix(body) else {
+            return Ok(false);
+        };
+
+        let (name, args) = match Args::parse_command(rest) {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                let message = RoomMessageEventContent::text_plain(format!(
+                    "Could not parse the command: {error}"
+                ));
+                room.send(message).await?;
+                return Ok(true);
+            }
+        };
+
+        let ctx = CommandContext {
+            room,
+            sender,
+            args,
+            raw_args: after_word(rest),
+        };
+
+        let name = match name.as_deref().or(self.default_command) {
+            Some("help") | None => {
+                let help = self.help(ctx.args.get(0));
+                ctx.reply(help).await?;
+                return Ok(true);
+            }
+            Some(name) => name,
+        };
+
+        let Some(command) = self.find(name) else {
+            ctx.reply(format!(
+                "Unknown command `{name}`, see `{} help`",
+                self.prefix
+            ))
+            .await?;
+            return Ok(true);
+        };
+
+        command.run(ctx).await?;
+
+        Ok(true)
+    }
+
+    /// Whether a message is meant for the bot.
+    pub fn is_command(&self, body: &str) -> bool {
+        self.strip_prefix(body).is_some()
+    }
+
+    /// The message without the prefix, if it starts with it.
+    fn strip_prefix<'a>(&self, body: &'a str) -> Option<&'a str> {
+        let rest = body.strip_prefix(&self.prefix)?;
+        // Don't answer to `!oxygen` when the prefix is `!oxy`.
+        (rest.is_empty() || rest.starts_with(char::is_whitespace)).then_some(rest)
+    }
+
+    /// The help of a single command, or the list of all commands.
+    fn help(&self, name: Option<&str>) -> String {
+        let prefix = &self.prefix;
+        let mut help = String::new();
+
+        if let Some(name) = name {
+            match self.find(name) {
+                Some(command) => {
+                    let _ = writeln!(help, "{}", command.description());
+                    let _ = write!(help, "Usage: {prefix} {}", usage_line(command));
+                    if !command.aliases().is_empty() {
+                        let _ = write!(help, "\nAliases: {}", command.aliases().join(", "));
+                    }
+                }
+                None => {
+                    let _ = write!(help, "Unknown command `{name}`");
+                }
+            }
+            return help;
+        }
+
+        let _ = writeln!(help, "Commands:");
+        let _ = writeln!(
+            help,
+            "  {prefix} help [command] — List the commands, or show the usage of one"
+        );
+        for command in &self.commands {
+            let _ = writeln!(
+                help,
+                "  {prefix} {} — {}",
+                usage_line(command.as_ref()),
+                command.description()
+            );
+        }
+
+        help.trim_end().to_owned()
+    }
+}
+
+/// The input after its first word, without the whitespace around it.
+pub(crate) fn after_word(input: &str) -> &str {
+    let input = input.trim_start();
+    input
+        .find(char::is_whitespace)
+        .map_or("", |end| input[end..].trim())
+}
+
+fn usage_line(command: &dyn Command) -> String {
+    match command.usage() {
+        "" => command.name().to_owned(),
+        usage => format!("{} {usage}", command.name()),
+    }
+}
@@ -0,0 +1,218 @@
+use futures_util::future::BoxFuture;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId, UserId};
+use std::{fmt::Write, sync::Arc};
+
+use super::{after_word, Command, CommandContext};
+use crate::{
+    error::BotResult,
+    quotes::{Quote, QuoteStore},
+};
+
+/// The maximum number of quotes listed by a search.
+const MAX_SEARCH_RESULTS: usize = 5;
+
+/// Manage the quote database.
+pub struct QuoteCommand {
+    store: Arc<QuoteStore>,
+
+    /// The room where any quote can be deleted.
+    admin_room: Option<OwnedRoomId>,
+}
+
+impl QuoteCommand {
+    pub fn new(store: Arc<QuoteStore>, admin_room: Option<OwnedRoomId>) -> Self {
+        Self { store, admin_room }
+    }
+
+    /// Whether `sender` can delete `quote` from `room_id`: only the user who
+    /// added it can, or anyone in the admin room.
+    fn can_delete(&self, quote: &Quote, sender: &UserId, room_id: &RoomId) -> bool {
+        quote.added_by.as_deref() == Some(sender) || self.admin_room.as_deref() == Some(room_id)
+    }
+}
+
+impl Command for QuoteCommand {
+    fn name(&self) -> &'static str {
+        "quote"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["q"]
+    }
+
+    fn usage(&self) -> &'static str {
+        "add [--tags=a,b] <text> | random [tag] | search <text> | del <id>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Curate the quotes of the rooms"
+    }
+
+    fn run<'a>(&'a self, ctx: CommandContext<'a>) -> BoxFuture<'a, BotResult> {
+        Box::pin(async move {
+            match ctx.args.get(0) {
+                Some("add") => {
+                    let (tags, text) = parse_add(after_word(ctx.raw_args));
+                    if text.is_empty() {
+                        return ctx.reply("What should I quote?").await;
+                    }
+
+                    let quote = self
+                        .store
+                        .add(text.to_owned(), tags, ctx.sender.to_owned())
+                        .await?;
+                    ctx.reply(format!("Added quote #{}", quote.id)).await
+                }
+                Some("random") | None => {
+                    let tag = ctx.args.get(1);
+                    match self.store.random(tag).await {
+                        Some(quote) => ctx.reply(format_quote(&quote)).await,
+                        None => match tag {
+                            Some(tag) => ctx.reply(format!("No quote tagged `{tag}`")).await,
+                            None => ctx.reply("There are no quotes yet").await,
+                        },
+                    }
+                }
+                Some("search") => {
+                    let Some(text) = ctx.args.rest(1) else {
+                        return ctx.reply("What should I search for?").await;
+                    };
+
+                    let quotes = self.store.search(&text).await;
+                    if quotes.is_empty() {
+                        return ctx.reply(format!("No quote contains “{text}”")).await;
+                    }
+
+                    let mut reply = String::new();
+                    for quote in quotes.iter().take(MAX_SEARCH_RESULTS) {
+                        let _ = writeln!(reply, "{}", format_quote(quote));
+                    }
+                    if quotes.len() > MAX_SEARCH_RESULTS {
+                        let _ = write!(reply, "…and {} more", quotes.len() - MAX_SEARCH_RESULTS);
+                    }
+                    ctx.reply(reply.trim_end()).await
+                }
+                Some("del") => {
+                    let Some(id) = ctx
+                        .args
+                        .get(1)
+                        .and_then(|id| id.trim_start_matches('#').parse().ok())
+                    else {
+                        return ctx.reply("Which quote should I delete?").await;
+                    };
+
+                    let Some(quote) = self.store.get(id).await else {
+                        return ctx.reply(format!("There is no quote #{id}")).await;
+                    };
+                    if !self.can_delete(&quote, ctx.sender, ctx.room.room_id()) {
+                        return ctx
+                            .reply(format!(
+                                "Only the user who added quote #{id} can delete it, or anyone in the admin room"
+                            ))
+                            .await;
+                    }
+
+                    match self.store.remove(id).await? {
+                        Some(quote) => ctx.reply(format!("Deleted quote #{}", quote.id)).await,
+                        None => ctx.reply(format!("There is no quote #{id}")).await,
+                    }
+                }
+                Some(other) => {
+                    ctx.reply(format!(
+                        "Unknown quote command `{other}`, expected add, random, search or del"
+                    ))
+                    .await
+                }
+            }
+        })
+    }
+}
+
+/// Split the tags of `quote add` from the text of the quote.
+///
+/// Only a leading `--tags=a,b` is an option, the rest is kept as sent.
+fn parse_add(raw: &str) -> (Vec<String>, &str) {
+    let Some(option) = raw.strip_prefix("--tags=") else {
+        return (Vec::new(), raw);
+    };
+    let (tags, text) = option
+        .split_once(char::is_whitespace)
+        .unwrap_or((option, ""));
+    let tags = tags
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(ToOwned::to_owned)
+        .collect();
+    (tags, text.trim_start())
+}
+
+fn format_quote(quote: &Quote) -> String {
+    let mut formatted = format!("#{} “{}”", quote.id, quote.text);
+    if let Some(added_by) = &quote.added_by {
+        let _ = write!(formatted, " — added by {added_by}");
+    }
+    if !quote.tags.is_empty() {
+        let _ = write!(formatted, " [{}]", quote.tags.join(", "));
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{
+        owned_room_id, owned_user_id, room_id, user_id, MilliSecondsSinceUnixEpoch,
+    };
+
+    use super::*;
+
+    #[test]
+    fn add_keeps_the_text_as_sent() {
+        assert_eq!(parse_add("Don't panic"), (vec![], "Don't panic"));
+        assert_eq!(
+            parse_add(r#"--tags=Fool,, wisdom "Know thyself" --really"#),
+            (vec!["Fool".to_owned()], r#"wisdom "Know thyself" --really"#)
+        );
+        assert_eq!(
+            parse_add("--tags=a,b  It's --tags=c"),
+            (vec!["a".to_owned(), "b".to_owned()], "It's --tags=c")
+        );
+        assert_eq!(parse_add("--tags=a"), (vec!["a".to_owned()], ""));
+        assert_eq!(after_word(" add  Don't panic "), "Don't panic");
+        assert_eq!(after_word("add"), "");
+    }
+
+    #[tokio::test]
+    async fn only_the_author_or_the_admin_room_can_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(
+            QuoteStore::load(dir.path(), &Default::default())
+                .await
+                .unwrap(),
+        );
+        let command = QuoteCommand::new(store, Some(owned_room_id!("!admin:example.org")));
+
+        let quote = Quote {
+            id: 1,
+            text: "Know thyself.".to_owned(),
+            tags: vec![],
+            added_by: Some(owned_user_id!("@alice:example.org")),
+            added_at: MilliSecondsSinceUnixEpoch::now(),
+        };
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room = room_id!("!room:example.org");
+        let admin_room = room_id!("!admin:example.org");
+
+        assert!(command.can_delete(&quote, alice, room));
+        assert!(!command.can_delete(&quote, bob, room));
+        assert!(command.can_delete(&quote, bob, admin_room));
+
+        let seeded = Quote {
+            added_by: None,
+            ..quote
+        };
+        assert!(!command.can_delete(&seeded, alice, room));
+        assert!(command.can_delete(&seeded, alice, admin_room));
+    }
+}
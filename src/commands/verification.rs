@@ -0,0 +1,70 @@
+use futures_util::future::BoxFuture;
+use std::sync::Arc;
+
+use super::{Command, CommandContext};
+use crate::{error::BotResult, verification::Verifier};
+
+/// Answer the verifications waiting for an operator, from the admin room.
+///
+/// Any member of the admin room can answer them.
+pub struct VerificationCommand {
+    verifier: Arc<Verifier>,
+}
+
+impl VerificationCommand {
+    pub fn new(verifier: Arc<Verifier>) -> Self {
+        Self { verifier }
+    }
+}
+
+impl Command for VerificationCommand {
+    fn name(&self) -> &'static str {
+        "verification"
+    }
+
+    fn usage(&self) -> &'static str {
+        "list | confirm <id> | cancel <id>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Answer the verifications waiting for an operator, in the admin room"
+    }
+
+    fn run<'a>(&'a self, ctx: CommandContext<'a>) -> BoxFuture<'a, BotResult> {
+        Box::pin(async move {
+            if !self.verifier.is_admin_room(ctx.room.room_id()) {
+                return ctx
+                    .reply("Verifications can only be answered in the admin room")
+                    .await;
+            }
+
+            match ctx.args.get(0) {
+                Some("list") | None => ctx.reply(self.verifier.list()).await,
+                Some(action @ ("confirm" | "cancel")) => {
+                    let Some(id) = ctx
+                        .args
+                        .get(1)
+                        .and_then(|id| id.trim_start_matches('#').parse().ok())
+                    else {
+                        return ctx
+                            .reply(format!("Which verification should I {action}?"))
+                            .await;
+                    };
+
+                    let reply = self.verifier.answer(
+                        id,
+                        action == "confirm",
+                        format!("{} in the admin room", ctx.sender),
+                    );
+                    ctx.reply(reply).await
+                }
+                Some(other) => {
+                    ctx.reply(format!(
+                        "Unknown verification command `{other}`, expected list, confirm or cancel"
+                    ))
+                    .await
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,517 @@
+use anyhow::{bail, Context};
+use log::warn;
+use matrix_sdk::ruma::{OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomId};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// The name of the config file looked up in the data directory.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// The bot configuration, loaded from a TOML file.
+///
+/// Example:
+///
+/// ```toml
+/// command_prefix = "!oxy"
+/// error_replies = false
+///
+/// [timeline]
+/// rooms = ["!EpdAGtDMTYZSR4VT5cG9:beeper.local", "#general:beeper.local"]
+/// backfill = 10
+///
+/// [catch_up]
+/// enabled = true
+/// max_age_secs = 3600
+/// max_count = 50
+///
+/// [verification]
+/// allowed_users = ["@admin:beeper.local"]
+/// confirm = "operator"
+/// admin_room = "!EpdAGtDMTYZSR4VT5cG9:beeper.local"
+/// timeout_secs = 300
+///
+/// [[rooms]]
+/// id = "!EpdAGtDMTYZSR4VT5cG9:beeper.local"
+/// commands = true
+/// responders = true
+///
+/// [[responders]]
+/// sender = "@someone:beeper.local"
+/// respond = { quotes = "fool" }
+/// probability = 0.5
+/// cooldown_secs = 60
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The prefix that marks a message as a command for the bot.
+    pub command_prefix: String,
+
+    /// Whether to tell the room when handling one of its messages failed.
+    pub error_replies: bool,
+
+    /// The rooms the bot knows about and how it behaves in each of them.
+    pub rooms: Vec<RoomConfig>,
+
+    /// Which room timelines to follow.
+    pub timeline: TimelineConfig,
+
+    /// What to do with the messages sent while the bot was offline.
+    pub catch_up: CatchUpConfig,
+
+    /// Who can verify the bot's device, and how.
+    pub verification: VerificationConfig,
+
+    /// The rules to automatically respond to messages.
+    pub responders: Vec<ResponderRule>,
+
+    /// Deprecated, the users to respond to with a `fool` quote whenever they
+    /// speak. Turned into `responders` when loading.
+    targeted_users: Vec<OwnedUserId>,
+
+    /// Deprecated, named lists of quotes. Imported into the quote database
+    /// with the name of the list as tag.
+    pub quote_lists: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            command_prefix: "!oxy".to_owned(),
+            error_replies: false,
+            rooms: Vec::new(),
+            timeline: TimelineConfig::default(),
+            catch_up: CatchUpConfig::default(),
+            verification: VerificationConfig::default(),
+            responders: Vec::new(),
+            targeted_users: Vec::new(),
+            quote_lists: BTreeMap::new(),
+        }
+    }
+}
+
+/// The per-room behaviour of the bot.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    /// The ID of the room.
+    pub id: OwnedRoomId,
+
+    /// Whether to answer commands in this room.
+    #[serde(default = "default_true")]
+    pub commands: bool,
+
+    /// Whether to run the responders in this room.
+    ///
+    /// Named `quotes` in older configs.
+    #[serde(default = "default_true", alias = "quotes")]
+    pub responders: bool,
+
+    /// Deprecated, whether to watch the timeline of this room. Added to
+    /// `timeline.rooms` when loading.
+    #[serde(default)]
+    watch_timeline: bool,
+}
+
+/// The rooms whose timeline is followed.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimelineConfig {
+    /// The rooms to watch.
+    pub rooms: Vec<RoomSelector>,
+
+    /// How many events to load from the past when starting to watch a room.
+    pub backfill: u16,
+}
+
+impl Default for TimelineConfig {
+    fn default() -> Self {
+        Self {
+            rooms: Vec::new(),
+            backfill: 10,
+        }
+    }
+}
+
+/// How to handle the messages sent while the bot was offline.
+///
+/// When enabled, the events received since the last persisted sync token go
+/// through the handlers on startup, so commands sent while the bot was down
+/// are answered. Otherwise they are skipped.
+///
+/// When the homeserver sends a `limited` timeline for a room, the events
+/// before it aren't fetched, so the messages in the gap are never handled.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CatchUpConfig {
+    /// Whether to handle the missed messages.
+    pub enabled: bool,
+
+    /// Skip the missed messages older than this.
+    pub max_age_secs: u64,
+
+    /// Answer at most this many missed messages, the others don't count.
+    pub max_count: usize,
+}
+
+impl Default for CatchUpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_age_secs: 60 * 60,
+            max_count: 50,
+        }
+    }
+}
+
+/// Who can verify the bot's device, and how verifications are confirmed.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerificationConfig {
+    /// The users allowed to verify the bot, other requests are cancelled.
+    ///
+    /// Everyone is allowed when empty.
+    pub allowed_users: Vec<OwnedUserId>,
+
+    /// How to confirm that the emojis, numbers or scanned QR code match.
+    pub confirm: ConfirmMethod,
+
+    /// The room where verifications are posted for an operator to confirm.
+    ///
+    /// Anyone who can send messages in it can confirm verifications, so it
+    /// should only have trusted members.
+    pub admin_room: Option<OwnedRoomId>,
+
+    /// Whether to listen on a local socket in the data directory for an
+    /// operator to confirm verifications.
+    pub control_socket: bool,
+
+    /// How long to wait for a confirmation before cancelling.
+    pub timeout_secs: u64,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            allowed_users: Vec::new(),
+            confirm: ConfirmMethod::Stdin,
+            admin_room: None,
+            control_socket: false,
+            timeout_secs: 5 * 60,
+        }
+    }
+}
+
+/// How a verification is confirmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfirmMethod {
+    /// Ask on the terminal, for interactive runs.
+    Stdin,
+
+    /// Confirm without comparing, only for the allowed users.
+    Auto,
+
+    /// Wait for an operator in the admin room or on the control socket.
+    Operator,
+}
+
+/// A way to designate rooms: `!id:server`, `#alias:server` or `*` for all the
+/// joined rooms.
+#[derive(Debug, Clone)]
+pub enum RoomSelector {
+    Id(OwnedRoomId),
+    Alias(OwnedRoomAliasId),
+    AllJoined,
+}
+
+impl<'de> Deserialize<'de> for RoomSelector {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let selector = String::deserialize(deserializer)?;
+        match selector.chars().next() {
+            Some('*') if selector == "*" => Ok(Self::AllJoined),
+            Some('!') => selector
+                .try_into()
+                .map(Self::Id)
+                .map_err(serde::de::Error::custom),
+            Some('#') => selector
+                .try_into()
+                .map(Self::Alias)
+                .map_err(serde::de::Error::custom),
+            _ => Err(serde::de::Error::custom(format!(
+                "expected a room ID, a room alias or `*`, got {selector:?}"
+            ))),
+        }
+    }
+}
+
+/// A rule to automatically respond to messages.
+///
+/// All the given conditions must match for the rule to trigger.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponderRule {
+    /// Only match messages from this user.
+    pub sender: Option<OwnedUserId>,
+
+    /// Only match messages in this room.
+    pub room: Option<OwnedRoomId>,
+
+    /// Only match messages whose body matches this regex.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub body: Option<Regex>,
+
+    /// Only match messages of this type, like `text`, `emote` or `m.image`.
+    pub msgtype: Option<String>,
+
+    /// How to respond.
+    pub respond: Response,
+
+    /// The probability to respond when the rule matches, between 0 and 1.
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+
+    /// The minimum time between two responses of this rule in a room.
+    #[serde(default)]
+    pub cooldown_secs: u64,
+}
+
+/// How a responder responds.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Response {
+    /// Send a random quote with this tag from the quote database.
+    Quotes(String),
+
+    /// Send a message from a template.
+    ///
+    /// `{sender}`, `{room}` and `{body}` are replaced by the sender, room ID
+    /// and body of the message, and `{0}`, `{1}` or `{name}` by the capture
+    /// groups of the `body` regex.
+    Template(String),
+
+    /// React to the message with this key.
+    Reaction(String),
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(pattern) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    Regex::new(&pattern)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    /// Load the config from `path`, or from the data directory if no path is
+    /// given.
+    ///
+    /// A missing config file in the data directory is not an error, the
+    /// default config is used instead. An explicitly given path must exist.
+    pub fn load(path: Option<&Path>, data_dir: &Path) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => {
+                let path = Self::default_path(data_dir);
+                if !path.exists() {
+                    println!(
+                        "No config file found in '{}', using the defaults",
+                        path.to_string_lossy()
+                    );
+                    return Ok(Self::default());
+                }
+                path
+            }
+        };
+
+        Self::from_file(&path)
+    }
+
+    /// Read and validate the config file at `path`.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file '{}'", path.to_string_lossy()))?;
+
+        Self::parse(&content)
+            .with_context(|| format!("Invalid config file '{}'", path.to_string_lossy()))
+    }
+
+    /// Parse and validate a config from its TOML representation.
+    ///
+    /// Syntax and type errors are reported by `toml` with the location of the
+    /// offending key, and semantic errors are prefixed with the key path.
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut config: Self = toml::from_str(content)?;
+        config.migrate_deprecated_keys();
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Turn the keys of older configs into the ones replacing them.
+    fn migrate_deprecated_keys(&mut self) {
+        if !self.targeted_users.is_empty() {
+            warn!("`targeted_users` is deprecated, use `[[responders]]` with `respond = {{ quotes = \"fool\" }}` instead");
+            for user_id in self.targeted_users.drain(..) {
+                self.responders.push(ResponderRule {
+                    sender: Some(user_id),
+                    room: None,
+                    body: None,
+                    msgtype: None,
+                    respond: Response::Quotes("fool".to_owned()),
+                    probability: 1.0,
+                    cooldown_secs: 0,
+                });
+            }
+        }
+        for room in &self.rooms {
+            if !room.watch_timeline {
+                continue;
+            }
+            warn!(
+                "`rooms.watch_timeline` is deprecated, add `{}` to `timeline.rooms` instead",
+                room.id
+            );
+            let already_watched = self
+                .timeline
+                .rooms
+                .iter()
+                .any(|selector| matches!(selector, RoomSelector::Id(id) if *id == room.id));
+            if !already_watched {
+                self.timeline.rooms.push(RoomSelector::Id(room.id.clone()));
+            }
+        }
+        if !self.quote_lists.is_empty() {
+            warn!("`quote_lists` is deprecated, its quotes are imported into the quote database and can be removed from the config");
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.command_prefix.trim().is_empty() {
+            bail!("`command_prefix`: must not be empty");
+        }
+        if self.command_prefix.chars().any(char::is_whitespace) {
+            bail!(
+                "`command_prefix`: must not contain whitespace, got {:?}",
+                self.command_prefix
+            );
+        }
+
+        let mut seen_rooms = HashSet::new();
+        for (i, room) in self.rooms.iter().enumerate() {
+            if !seen_rooms.insert(&room.id) {
+                bail!("`rooms[{i}].id`: duplicate room `{}`", room.id);
+            }
+        }
+
+        for (i, rule) in self.responders.iter().enumerate() {
+            if !(0.0..=1.0).contains(&rule.probability) {
+                bail!(
+                    "`responders[{i}].probability`: must be between 0 and 1, got {}",
+                    rule.probability
+                );
+            }
+            if let Response::Quotes(tag) = &rule.respond {
+                if tag.trim().is_empty() {
+                    bail!("`responders[{i}].respond.quotes`: must not be empty");
+                }
+            }
+        }
+
+        let verification = &self.verification;
+        if verification.confirm == ConfirmMethod::Auto && verification.allowed_users.is_empty() {
+            bail!("`verification.confirm`: `auto` needs `verification.allowed_users`, otherwise anyone can verify the bot");
+        }
+        if verification.confirm == ConfirmMethod::Operator
+            && verification.admin_room.is_none()
+            && !verification.control_socket
+        {
+            bail!("`verification.confirm`: `operator` needs `verification.admin_room` or `verification.control_socket`");
+        }
+        if cfg!(not(unix)) && verification.control_socket {
+            bail!("`verification.control_socket`: only supported on Unix");
+        }
+        if verification.timeout_secs == 0 {
+            bail!("`verification.timeout_secs`: must be greater than 0");
+        }
+
+        Ok(())
+    }
+
+    /// Get the config of a room, if it is declared.
+    pub fn room(&self, room_id: &RoomId) -> Option<&RoomConfig> {
+        self.rooms.iter().find(|room| room.id == room_id)
+    }
+
+    /// The path of the config file in the data directory.
+    pub fn default_path(data_dir: &Path) -> PathBuf {
+        data_dir.join(CONFIG_FILE_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targeted_users_become_responders() {
+        let config = Config::parse(
+            r#"
+            targeted_users = ["@someone:example.org"]
+
+            [[rooms]]
+            id = "!room:example.org"
+            quotes = false
+            "#,
+        )
+        .unwrap();
+
+        assert!(!config.rooms[0].responders);
+        assert_eq!(config.responders.len(), 1);
+        let rule = &config.responders[0];
+        assert_eq!(
+            rule.sender.as_ref().map(|sender| sender.as_str()),
+            Some("@someone:example.org")
+        );
+        assert!(matches!(&rule.respond, Response::Quotes(tag) if tag == "fool"));
+    }
+
+    #[test]
+    fn watch_timeline_becomes_timeline_rooms() {
+        let config = Config::parse(
+            r#"
+            [[rooms]]
+            id = "!watched:example.org"
+            watch_timeline = true
+
+            [[rooms]]
+            id = "!other:example.org"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.timeline.rooms.len(), 1);
+        assert!(matches!(
+            &config.timeline.rooms[0],
+            RoomSelector::Id(id) if id.as_str() == "!watched:example.org"
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::bail;
+use log::warn;
+use std::{
+    fs::{DirBuilder, Permissions},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::UnixStream as StdUnixStream,
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+use crate::verification::Verifier;
+
+/// The name of the control socket in the data directory.
+pub const CONTROL_SOCKET_FILE_NAME: &str = "control.sock";
+
+/// The directory in which the control socket is created before it is moved
+/// to the data directory.
+const CONTROL_SOCKET_STAGING_DIR_NAME: &str = "control.sock.d";
+
+/// A local socket for an operator to answer the verifications, with a
+/// command per line: `list`, `confirm <id>` or `cancel <id>`.
+///
+/// Only the user running the bot can connect to it. The socket is removed
+/// when this is dropped.
+#[derive(Debug)]
+pub struct ControlSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ControlSocket {
+    /// Listen on the control socket of the data directory.
+    pub fn bind(data_dir: &Path, verifier: Arc<Verifier>) -> anyhow::Result<Self> {
+        let path = data_dir.join(CONTROL_SOCKET_FILE_NAME);
+
+        if path.exists() {
+            if StdUnixStream::connect(&path).is_ok() {
+                bail!(
+                    "The control socket '{}' is in use, is another instance running?",
+                    path.to_string_lossy()
+                );
+            }
+            // Left by a run that didn't stop cleanly.
+            std::fs::remove_file(&path)?;
+        }
+
+        // Bind in a directory only we can enter, so nobody can connect before
+        // the permissions of the socket are set.
+        let staging_dir = data_dir.join(CONTROL_SOCKET_STAGING_DIR_NAME);
+        if staging_dir.exists() {
+            std::fs::remove_dir_all(&staging_dir)?;
+        }
+        DirBuilder::new().mode(0o700).create(&staging_dir)?;
+        let staging_path = staging_dir.join(CONTROL_SOCKET_FILE_NAME);
+        let listener = UnixListener::bind(&staging_path)?;
+        std::fs::set_permissions(&staging_path, Permissions::from_mode(0o600))?;
+        std::fs::rename(&staging_path, &path)?;
+        std::fs::remove_dir(&staging_dir)?;
+
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let verifier = verifier.clone();
+                        tokio::spawn(async move {
+                            if let Err(error) = serve(stream, &verifier).await {
+                                warn!("Control socket connection failed: {error}");
+                            }
+                        });
+                    }
+                    Err(error) => {
+                        warn!("Unable to accept a control socket connection: {error}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { path, task })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Err(error) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Unable to remove the control socket '{}': {error}",
+                self.path.to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Answer the commands of a connection until it is closed.
+async fn serve(stream: UnixStream, verifier: &Verifier) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let mut words = line.split_whitespace();
+        let reply = match (words.next(), words.next(), words.next()) {
+            (None, ..) => continue,
+            (Some("list"), None, _) => verifier.list(),
+            (Some(action @ ("confirm" | "cancel")), Some(id), None) => {
+                match id.trim_start_matches('#').parse() {
+                    Ok(id) => {
+                        verifier.answer(id, action == "confirm", "the control socket".to_owned())
+                    }
+                    Err(_) => format!("Invalid verification ID `{id}`"),
+                }
+            }
+            _ => "Unknown command, expected `list`, `confirm <id>` or `cancel <id>`".to_owned(),
+        };
+
+        writer.write_all(reply.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+
+    use super::*;
+    use crate::{config::Config, error::ErrorReporter, shutdown::Shutdown};
+
+    fn verifier(data_dir: &Path) -> Arc<Verifier> {
+        let shutdown = Arc::new(Shutdown::default());
+        let reporter = Arc::new(ErrorReporter::new(false, shutdown.clone()));
+        Arc::new(Verifier::new(
+            Arc::new(Config::default()),
+            data_dir,
+            reporter,
+            shutdown,
+        ))
+    }
+
+    #[tokio::test]
+    async fn socket_is_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONTROL_SOCKET_FILE_NAME);
+        // Left by a run that didn't stop cleanly.
+        std::fs::create_dir(dir.path().join(CONTROL_SOCKET_STAGING_DIR_NAME)).unwrap();
+
+        let socket = ControlSocket::bind(dir.path(), verifier(dir.path())).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert!(!dir.path().join(CONTROL_SOCKET_STAGING_DIR_NAME).exists());
+
+        let error = ControlSocket::bind(dir.path(), verifier(dir.path())).unwrap_err();
+        assert!(error.to_string().contains("is in use"));
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn one_command_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let _socket = ControlSocket::bind(dir.path(), verifier(dir.path())).unwrap();
+
+        let stream = UnixStream::connect(dir.path().join(CONTROL_SOCKET_FILE_NAME))
+            .await
+            .unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(b"list\n\n  confirm #1 \ncancel one\nlist all\nhello\n")
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut replies = Vec::new();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            replies.push(line);
+        }
+        assert_eq!(
+            replies,
+            [
+                "No verification is waiting for a confirmation",
+                "There is no verification #1 waiting for a confirmation",
+                "Invalid verification ID `one`",
+                "Unknown command, expected `list`, `confirm <id>` or `cancel <id>`",
+                "Unknown command, expected `list`, `confirm <id>` or `cancel <id>`",
+            ]
+        );
+    }
+}
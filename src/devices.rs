@@ -0,0 +1,365 @@
+use anyhow::{bail, Context};
+use matrix_sdk::{
+    encryption::{identities::Device, LocalTrust},
+    ruma::{
+        api::client::{device::Device as AccountDevice, keys::get_keys},
+        DeviceId, OwnedDeviceId, UserId,
+    },
+    Client,
+};
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    auth,
+    error::{BotError, BotResult},
+};
+
+/// A device of a user, as listed by the bot.
+#[derive(Debug)]
+pub struct DeviceRow {
+    pub device_id: OwnedDeviceId,
+    pub display_name: Option<String>,
+    pub trust: Trust,
+
+    /// Whether this is the device of the bot.
+    pub current: bool,
+
+    /// When the device was last active, only known for the bot's own account.
+    pub last_seen: Option<SystemTime>,
+}
+
+/// Whether the bot trusts a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    Verified,
+    Unverified,
+
+    /// Doesn't receive the room keys of the bot.
+    Blacklisted,
+
+    /// Not tracked by the bot, which doesn't share an encrypted room with the
+    /// user.
+    Unknown,
+}
+
+impl Trust {
+    fn of(device: &Device) -> Self {
+        if device.is_blacklisted() {
+            Self::Blacklisted
+        } else if device.is_verified() {
+            Self::Verified
+        } else {
+            Self::Unverified
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Verified => "verified",
+            Self::Unverified => "unverified",
+            Self::Blacklisted => "blacklisted",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// List the devices of a user, sorted by ID.
+///
+/// The devices are queried from the homeserver, since the store only has
+/// those of the users the bot tracks.
+pub async fn list(client: &Client, user_id: &UserId) -> BotResult<Vec<DeviceRow>> {
+    let own_device_id = client.device_id();
+
+    let mut request = get_keys::v3::Request::new();
+    request.device_keys.insert(user_id.to_owned(), Vec::new());
+    let mut response = client.send(request, None).await?;
+    let queried = response.device_keys.remove(user_id).unwrap_or_default();
+
+    // The homeserver only tells when the devices of our own account were
+    // last seen.
+    let last_seen = if client.user_id() == Some(user_id) {
+        client.devices().await?.devices
+    } else {
+        Vec::new()
+    };
+
+    let known = client.encryption().get_user_devices(user_id).await?;
+
+    let mut rows: Vec<_> = queried
+        .into_iter()
+        .map(|(device_id, keys)| {
+            let device = known.get(&device_id);
+            DeviceRow {
+                display_name: keys
+                    .deserialize()
+                    .ok()
+                    .and_then(|keys| keys.unsigned.device_display_name)
+                    .or_else(|| device.as_ref()?.display_name().map(ToOwned::to_owned)),
+                trust: device.as_ref().map_or(Trust::Unknown, Trust::of),
+                current: Some(&*device_id) == own_device_id,
+                last_seen: last_seen
+                    .iter()
+                    .find(|seen| seen.device_id == device_id)
+                    .and_then(|seen| seen.last_seen_ts)
+                    .and_then(|ts| ts.to_system_time()),
+                device_id,
+            }
+        })
+        .collect();
+    rows.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+    Ok(rows)
+}
+
+/// Set how much the bot trusts a device, overriding its verification state.
+pub async fn set_trust(
+    client: &Client,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    trust: LocalTrust,
+) -> anyhow::Result<()> {
+    let device = client
+        .encryption()
+        .get_device(user_id, device_id)
+        .await?
+        .with_context(|| format!("Unknown device {device_id} of {user_id}"))?;
+
+    device.set_local_trust(trust).await?;
+    Ok(())
+}
+
+/// The other devices of the bot's account that the bot logged in and that
+/// weren't seen for `max_age`.
+pub async fn stale(client: &Client, max_age: Duration) -> anyhow::Result<Vec<OwnedDeviceId>> {
+    let devices = client.devices().await?.devices;
+    Ok(filter_stale(
+        devices,
+        client.device_id(),
+        max_age,
+        SystemTime::now(),
+    ))
+}
+
+/// Keep the devices of the account that [`stale`] returns.
+fn filter_stale(
+    devices: Vec<AccountDevice>,
+    own_device_id: Option<&DeviceId>,
+    max_age: Duration,
+    now: SystemTime,
+) -> Vec<OwnedDeviceId> {
+    devices
+        .into_iter()
+        .filter(|device| Some(&*device.device_id) != own_device_id)
+        .filter(|device| device.display_name.as_deref() == Some(auth::DEVICE_DISPLAY_NAME))
+        .filter(|device| {
+            // Never seen is as stale as it gets.
+            device
+                .last_seen_ts
+                .and_then(|ts| ts.to_system_time())
+                .and_then(|last_seen| now.duration_since(last_seen).ok())
+                .is_none_or(|age| age >= max_age)
+        })
+        .map(|device| device.device_id)
+        .collect()
+}
+
+/// Delete devices of the bot's account, with the password of the account if
+/// the homeserver asks for it.
+pub async fn delete(
+    client: &Client,
+    device_ids: &[OwnedDeviceId],
+    password: Option<String>,
+) -> anyhow::Result<()> {
+    if client
+        .device_id()
+        .is_some_and(|own| device_ids.iter().any(|device_id| device_id == own))
+    {
+        bail!("Refusing to delete the device of the bot, log out instead");
+    }
+
+    let Err(error) = client.delete_devices(device_ids, None).await else {
+        return Ok(());
+    };
+    let Some(response) = error.as_uiaa_response() else {
+        return Err(error.into());
+    };
+
+    let user_id = client.user_id().ok_or(BotError::NotLoggedIn)?;
+    let auth = auth::password_auth(user_id, password, response)?;
+    client
+        .delete_devices(device_ids, Some(auth))
+        .await
+        .context("Unable to delete the devices")?;
+
+    Ok(())
+}
+
+/// Format devices as a plain text table.
+pub fn format_table(rows: &[DeviceRow]) -> String {
+    let cells: Vec<_> = rows.iter().map(cells).collect();
+    let mut widths = HEADERS.map(str::len);
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(HEADERS.map(ToOwned::to_owned)).chain(cells) {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(widths) {
+            let _ = write!(line, "{cell:<width$}  ");
+        }
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+    table.trim_end().to_owned()
+}
+
+/// Format devices as an HTML table.
+pub fn format_html_table(rows: &[DeviceRow]) -> String {
+    let mut table = String::from("<table>\n<tr>");
+    for header in HEADERS {
+        let _ = write!(table, "<th>{header}</th>");
+    }
+    table.push_str("</tr>\n");
+
+    for row in rows {
+        table.push_str("<tr>");
+        for cell in cells(row) {
+            let _ = write!(table, "<td>{}</td>", escape_html(&cell));
+        }
+        table.push_str("</tr>\n");
+    }
+
+    table.push_str("</table>");
+    table
+}
+
+const HEADERS: [&str; 4] = ["Device", "Name", "Trust", "Last seen"];
+
+fn cells(row: &DeviceRow) -> [String; 4] {
+    [
+        if row.current {
+            format!("{} (this bot)", row.device_id)
+        } else {
+            row.device_id.to_string()
+        },
+        row.display_name.clone().unwrap_or_else(|| "-".to_owned()),
+        row.trust.as_str().to_owned(),
+        row.last_seen
+            .map(format_last_seen)
+            .unwrap_or_else(|| "-".to_owned()),
+    ]
+}
+
+/// Format when a device was last seen, in days.
+fn format_last_seen(last_seen: SystemTime) -> String {
+    let days = SystemTime::now()
+        .duration_since(last_seen)
+        .unwrap_or_default()
+        .as_secs()
+        / (24 * 60 * 60);
+    match days {
+        0 => "today".to_owned(),
+        1 => "yesterday".to_owned(),
+        days => format!("{days} days ago"),
+    }
+}
+
+/// Escape text to put it in HTML.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{device_id, MilliSecondsSinceUnixEpoch};
+
+    use super::*;
+
+    fn row(device_id: &str, display_name: Option<&str>, trust: Trust, current: bool) -> DeviceRow {
+        DeviceRow {
+            device_id: device_id.into(),
+            display_name: display_name.map(ToOwned::to_owned),
+            trust,
+            current,
+            last_seen: None,
+        }
+    }
+
+    #[test]
+    fn table_is_aligned() {
+        let rows = [
+            row("BOTDEVICE", Some("oxybot client"), Trust::Verified, true),
+            row("PHONE", None, Trust::Blacklisted, false),
+        ];
+
+        let expected = [
+            "Device                Name           Trust        Last seen",
+            "BOTDEVICE (this bot)  oxybot client  verified     -",
+            "PHONE                 -              blacklisted  -",
+        ];
+        assert_eq!(format_table(&rows), expected.join("\n"));
+    }
+
+    #[test]
+    fn html_table_is_escaped() {
+        let rows = [row(
+            "<DEVICE>",
+            Some("Tom & \"Jerry\""),
+            Trust::Unknown,
+            false,
+        )];
+
+        assert_eq!(
+            format_html_table(&rows),
+            "<table>\n\
+             <tr><th>Device</th><th>Name</th><th>Trust</th><th>Last seen</th></tr>\n\
+             <tr><td>&lt;DEVICE&gt;</td><td>Tom &amp; &quot;Jerry&quot;</td><td>unknown</td><td>-</td></tr>\n\
+             </table>"
+        );
+    }
+
+    fn account_device(
+        device_id: &DeviceId,
+        display_name: Option<&str>,
+        last_seen: Option<SystemTime>,
+    ) -> AccountDevice {
+        let mut device = AccountDevice::new(device_id.to_owned());
+        device.display_name = display_name.map(ToOwned::to_owned);
+        device.last_seen_ts = last_seen
+            .map(|last_seen| MilliSecondsSinceUnixEpoch::from_system_time(last_seen).unwrap());
+        device
+    }
+
+    #[test]
+    fn stale_devices() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let name = Some(auth::DEVICE_DISPLAY_NAME);
+        let devices = vec![
+            // The bot itself, whenever it was last seen.
+            account_device(device_id!("CURRENT"), name, None),
+            account_device(device_id!("OLD"), name, Some(now - day * 31)),
+            account_device(device_id!("NEVER_SEEN"), name, None),
+            account_device(device_id!("RECENT"), name, Some(now - day)),
+            // Not logged in by the bot.
+            account_device(device_id!("PHONE"), Some("Phone"), None),
+            account_device(device_id!("UNNAMED"), None, None),
+        ];
+
+        assert_eq!(
+            filter_stale(devices, Some(device_id!("CURRENT")), day * 30, now),
+            [
+                device_id!("OLD").to_owned(),
+                device_id!("NEVER_SEEN").to_owned()
+            ]
+        );
+    }
+}
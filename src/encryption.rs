@@ -0,0 +1,197 @@
+use anyhow::{bail, Context};
+use matrix_sdk::{ruma::UserId, Client};
+use std::path::Path;
+
+use crate::{
+    auth::{self, prompt_secret},
+    error::BotError,
+    CLIENT_NAME,
+};
+
+/// Set up cross-signing for the account if needed, then a server-side backup
+/// of the room keys.
+///
+/// Returns the recovery key, which restores both on another device.
+pub async fn bootstrap(client: &Client, password: Option<String>) -> anyhow::Result<String> {
+    let encryption = client.encryption();
+    let user_id = client.user_id().ok_or(BotError::NotLoggedIn)?;
+
+    let has_cross_signing_keys = encryption
+        .cross_signing_status()
+        .await
+        .is_some_and(|status| status.is_complete());
+
+    if has_cross_signing_keys {
+        println!("Cross-signing is already set up on this device");
+    } else if encryption.get_user_identity(user_id).await?.is_some() {
+        // Bootstrapping again would replace the identity that the other
+        // sessions and users trust.
+        bail!(
+            "The account already has a cross-signing identity, restore it with `{CLIENT_NAME} encryption recover` and its recovery key, or verify the bot from another session with `{CLIENT_NAME} verify`"
+        );
+    } else {
+        bootstrap_cross_signing(client, user_id, password).await?;
+        println!("Cross-signing is set up");
+    }
+
+    println!("Setting up the key backup and uploading the room keys…");
+    let recovery_key = encryption
+        .recovery()
+        .enable()
+        .wait_for_backups_to_upload()
+        .await
+        .with_context(|| {
+            format!(
+                "Unable to set up the key backup, if the account already has one restore it with `{CLIENT_NAME} encryption recover`"
+            )
+        })?;
+
+    Ok(recovery_key)
+}
+
+/// Create and upload the cross-signing keys, with the password of the account
+/// if the homeserver asks for it.
+async fn bootstrap_cross_signing(
+    client: &Client,
+    user_id: &UserId,
+    password: Option<String>,
+) -> anyhow::Result<()> {
+    let encryption = client.encryption();
+
+    let Err(error) = encryption.bootstrap_cross_signing(None).await else {
+        return Ok(());
+    };
+    let Some(response) = error.as_uiaa_response() else {
+        return Err(error.into());
+    };
+
+    let auth = auth::password_auth(user_id, password, response)?;
+    encryption
+        .bootstrap_cross_signing(Some(auth))
+        .await
+        .context("Unable to upload the cross-signing keys")?;
+
+    Ok(())
+}
+
+/// Restore the cross-signing keys and the key backup of the account on this
+/// device, from the recovery key.
+pub async fn recover(client: &Client, recovery_key: Option<String>) -> anyhow::Result<()> {
+    let recovery_key = match recovery_key {
+        Some(recovery_key) => recovery_key,
+        None => prompt_secret("Recovery key: ")?,
+    };
+
+    let encryption = client.encryption();
+    encryption
+        .recovery()
+        .recover(recovery_key.trim())
+        .await
+        .context("Unable to recover the encryption of the account")?;
+
+    let has_cross_signing_keys = encryption
+        .cross_signing_status()
+        .await
+        .is_some_and(|status| status.is_complete());
+    println!(
+        "Cross-signing: {}",
+        if has_cross_signing_keys {
+            "restored"
+        } else {
+            "not set up"
+        }
+    );
+    println!(
+        "Key backup:    {}",
+        if encryption.backups().are_enabled().await {
+            "restored, room keys are downloaded when needed"
+        } else {
+            "not set up"
+        }
+    );
+
+    Ok(())
+}
+
+/// Export the room keys of the store to `path`, encrypted with `passphrase`.
+///
+/// Returns the number of exported keys.
+pub async fn export_keys(
+    client: &Client,
+    path: &Path,
+    passphrase: Option<String>,
+) -> anyhow::Result<usize> {
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => {
+            let passphrase = prompt_secret("Passphrase of the export: ")?;
+            if prompt_secret("Repeat the passphrase: ")? != passphrase {
+                bail!("The passphrases don't match");
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        bail!("The passphrase must not be empty");
+    }
+
+    // The keys are encrypted, but the passphrase could be weak: the file is
+    // created first so it is never readable by others, and never replaces an
+    // existing one.
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    if let Err(error) = options.open(path) {
+        if error.kind() == std::io::ErrorKind::AlreadyExists {
+            bail!("'{}' already exists", path.to_string_lossy());
+        }
+        return Err(error)
+            .with_context(|| format!("Unable to create '{}'", path.to_string_lossy()));
+    }
+
+    let mut count = 0;
+    let result = client
+        .encryption()
+        .export_room_keys(path.to_owned(), &passphrase, |_| {
+            count += 1;
+            true
+        })
+        .await;
+    if let Err(error) = result {
+        let _ = std::fs::remove_file(path);
+        return Err(error).context("Unable to export the room keys");
+    }
+
+    Ok(count)
+}
+
+/// Import the room keys exported to `path`, encrypted with `passphrase`.
+///
+/// Returns the number of new keys and the number of keys in the file.
+pub async fn import_keys(
+    client: &Client,
+    path: &Path,
+    passphrase: Option<String>,
+) -> anyhow::Result<(usize, usize)> {
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => prompt_secret("Passphrase of the export: ")?,
+    };
+
+    let result = client
+        .encryption()
+        .import_room_keys(path.to_owned(), &passphrase)
+        .await
+        .with_context(|| {
+            format!(
+                "Unable to import the room keys of '{}'",
+                path.to_string_lossy()
+            )
+        })?;
+
+    Ok((result.imported_count, result.total_count))
+}
@@ -0,0 +1,132 @@
+use log::error;
+use matrix_sdk::{
+    encryption::CryptoStoreError,
+    ruma::{events::room::message::RoomMessageEventContent, EventId, OwnedUserId},
+    HttpError, Room,
+};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::shutdown::Shutdown;
+
+/// The errors that can happen while handling events.
+#[derive(Debug, thiserror::Error)]
+pub enum BotError {
+    /// The client has no session, which shouldn't happen once we synced.
+    #[error("the client is not logged in")]
+    NotLoggedIn,
+
+    /// The SDK didn't create the object for a verification request we got.
+    #[error("no verification request {flow_id} from {user_id}")]
+    MissingVerificationRequest {
+        user_id: OwnedUserId,
+        flow_id: String,
+    },
+
+    #[error(transparent)]
+    Matrix(#[from] matrix_sdk::Error),
+
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    #[error(transparent)]
+    CryptoStore(#[from] CryptoStoreError),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+pub type BotResult<T = ()> = Result<T, BotError>;
+
+impl BotError {
+    /// A short description of the error that can be sent to a room.
+    ///
+    /// Details stay in the logs, they could leak information about the host.
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            Self::Http(_) | Self::Matrix(matrix_sdk::Error::Http(_)) => {
+                "Sorry, the homeserver didn't cooperate, try again later."
+            }
+            _ => "Sorry, something went wrong while handling this.",
+        }
+    }
+}
+
+/// Logs the errors of event handlers and counts them.
+#[derive(Debug)]
+pub struct ErrorReporter {
+    failures: AtomicU64,
+    reply_in_room: bool,
+    shutdown: Arc<Shutdown>,
+}
+
+impl ErrorReporter {
+    /// Create a reporter, which also replies to the room in which an event
+    /// failed to be handled if `reply_in_room` is set.
+    ///
+    /// The tasks it spawns are tracked by `shutdown`.
+    pub fn new(reply_in_room: bool, shutdown: Arc<Shutdown>) -> Self {
+        Self {
+            failures: AtomicU64::new(0),
+            reply_in_room,
+            shutdown,
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.failures.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Report the failure of the handler of an event in a room.
+    pub async fn report_room_event(
+        &self,
+        handler: &str,
+        room: &Room,
+        event_id: &EventId,
+        error: BotError,
+    ) {
+        let failures = self.count();
+        error!(
+            "[{}] {handler} failed on {event_id}: {error:#} ({failures} failures so far)",
+            room.room_id()
+        );
+
+        if self.reply_in_room {
+            let message = RoomMessageEventContent::text_plain(error.user_message());
+            if let Err(error) = room.send(message).await {
+                error!(
+                    "[{}] Unable to send the error reply: {error}",
+                    room.room_id()
+                );
+            }
+        }
+    }
+
+    /// Report the failure of a handler that isn't tied to a room.
+    pub fn report(&self, handler: &str, error: BotError) {
+        let failures = self.count();
+        error!("{handler} failed: {error:#} ({failures} failures so far)");
+    }
+
+    /// Spawn a task and report its failure, if any.
+    ///
+    /// A graceful shutdown waits for the task.
+    pub fn spawn<F>(self: &Arc<Self>, handler: String, task: F)
+    where
+        F: Future<Output = BotResult> + Send + 'static,
+    {
+        let reporter = self.clone();
+        let in_flight = self.shutdown.track();
+        tokio::spawn(async move {
+            let _in_flight = in_flight;
+            if let Err(error) = task.await {
+                reporter.report(&handler, error);
+            }
+        });
+    }
+}
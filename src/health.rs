@@ -0,0 +1,103 @@
+use std::{fmt, sync::Mutex, time::Instant};
+
+/// How the connection to the homeserver is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    /// The first sync didn't succeed yet.
+    Starting,
+
+    /// The last sync succeeded.
+    Healthy,
+
+    /// The last syncs failed, but they are retried.
+    Degraded,
+
+    /// The homeserver invalidated the session.
+    LoggedOut,
+
+    /// The sync failed in a way that can't be retried.
+    Failed,
+}
+
+impl fmt::Display for HealthState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Starting => "starting",
+            Self::Healthy => "healthy",
+            Self::Degraded => "degraded",
+            Self::LoggedOut => "logged out",
+            Self::Failed => "failed",
+        })
+    }
+}
+
+/// A snapshot of the health of the bot.
+#[derive(Debug, Clone)]
+pub struct HealthReport {
+    pub state: HealthState,
+
+    /// When the state last changed.
+    pub since: Instant,
+
+    /// When the last sync succeeded.
+    pub last_sync: Option<Instant>,
+
+    /// The number of syncs that failed since the last one that succeeded.
+    pub consecutive_failures: u32,
+}
+
+/// The health of the bot, updated by the sync loop and queried by the rest.
+#[derive(Debug)]
+pub struct Health {
+    report: Mutex<HealthReport>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            report: Mutex::new(HealthReport {
+                state: HealthState::Starting,
+                since: Instant::now(),
+                last_sync: None,
+                consecutive_failures: 0,
+            }),
+        }
+    }
+}
+
+impl Health {
+    /// The current health.
+    pub fn report(&self) -> HealthReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Record a successful sync.
+    pub fn sync_succeeded(&self) {
+        let mut report = self.report.lock().unwrap();
+        report.last_sync = Some(Instant::now());
+        report.consecutive_failures = 0;
+        set_state(&mut report, HealthState::Healthy);
+    }
+
+    /// Record a failed sync that will be retried.
+    ///
+    /// Returns the number of consecutive failures.
+    pub fn sync_failed(&self) -> u32 {
+        let mut report = self.report.lock().unwrap();
+        report.consecutive_failures += 1;
+        set_state(&mut report, HealthState::Degraded);
+        report.consecutive_failures
+    }
+
+    /// Record a failure that stops the sync.
+    pub fn set_stopped(&self, state: HealthState) {
+        set_state(&mut self.report.lock().unwrap(), state);
+    }
+}
+
+fn set_state(report: &mut HealthReport, state: HealthState) {
+    if report.state != state {
+        report.state = state;
+        report.since = Instant::now();
+    }
+}
@@ -0,0 +1,80 @@
+use anyhow::{bail, Context};
+use fs2::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    path::Path,
+};
+
+/// The name of the lock file in the data directory.
+pub const LOCK_FILE_NAME: &str = "lock";
+
+/// An exclusive lock on the data directory of a profile, released when
+/// dropped.
+///
+/// The store and the session can only be used by one process at a time, so
+/// `run` and the commands that restore the session hold it while they do.
+#[derive(Debug)]
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDirLock {
+    /// Lock the data directory, failing if another process already did.
+    pub fn acquire(data_dir: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(data_dir).with_context(|| {
+            format!(
+                "Unable to create the data directory '{}'",
+                data_dir.to_string_lossy()
+            )
+        })?;
+
+        let path = data_dir.join(LOCK_FILE_NAME);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Unable to open '{}'", path.to_string_lossy()))?;
+
+        if let Err(error) = file.try_lock_exclusive() {
+            if error.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+                bail!(
+                    "The data directory '{}' is in use by another oxybot process, stop it first",
+                    data_dir.to_string_lossy()
+                );
+            }
+            return Err(error)
+                .with_context(|| format!("Unable to lock '{}'", path.to_string_lossy()));
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_lock_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let lock = DataDirLock::acquire(dir.path()).unwrap();
+        let error = DataDirLock::acquire(dir.path()).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("in use by another oxybot process"));
+
+        drop(lock);
+        DataDirLock::acquire(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn creates_the_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("profiles").join("new");
+
+        DataDirLock::acquire(&data_dir).unwrap();
+        assert!(data_dir.join(LOCK_FILE_NAME).exists());
+    }
+}
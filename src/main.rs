@@ -1,24 +1,68 @@
-use futures_util::StreamExt;
-use log::info;
+use anyhow::bail;
+use auth::Reauthenticated;
+use catch_up::CatchUp;
+use clap::Parser;
+use cli::{
+    Cli, Command, DevicesCommand, EncryptionCommand, KeysCommand, LoginArgs, Profile, VerifyArgs,
+};
+use commands::{CommandRouter, HealthCommand, Hello, QuoteCommand, VerificationCommand};
+use config::Config;
+use error::{BotError, BotResult, ErrorReporter};
+use futures_util::future::join_all;
+use health::{Health, HealthState};
+use lock::DataDirLock;
+use log::{error, info, warn};
 use matrix_sdk::{
     config::SyncSettings,
-    deserialized_responses::TimelineEvent,
+    encryption::LocalTrust,
+    event_handler::Ctx,
     ruma::{
         api::client::filter::FilterDefinition,
-        events::room::message::{
-            MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
-        },
-        room_id, user_id,
+        events::room::message::{MessageType, OriginalSyncRoomMessageEvent},
+        DeviceId, UserId,
     },
     Client, Error, LoopCtrl, Room, RoomState,
 };
-use matrix_sdk_ui::timeline::{PaginationOptions, RoomExt};
-use rand::Rng;
-use std::path::Path;
+use quotes::QuoteStore;
+use responder::Responders;
+use retry::Failure;
+use shutdown::{Shutdown, SHUTDOWN_TIMEOUT};
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use sync_token::SyncTokenStore;
+use timeline::TimelineWatcher;
+use tracing::Instrument;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter};
+use verification::Verifier;
 
 mod auth;
+mod catch_up;
+mod cli;
+mod commands;
+mod config;
+#[cfg(unix)]
+mod control;
+mod devices;
+mod encryption;
+mod error;
+mod health;
+mod lock;
+mod quotes;
+mod responder;
+mod retry;
+mod secrets;
+mod shutdown;
+mod sync_token;
+mod timeline;
+mod verification;
 
 fn init_custom_logger() {
     let crate_name = "oxybot";
@@ -34,48 +78,593 @@ fn init_custom_logger() {
 
 const CLIENT_NAME: &str = "oxybot";
 
+/// The exit code when the bot stopped before the work in flight finished.
+const EXIT_INTERRUPTED: u8 = 2;
+
+/// The exit code when the session must be logged in again by an operator.
+const EXIT_LOGGED_OUT: u8 = 3;
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
     init_custom_logger();
 
+    match &cli.command {
+        Some(Command::Login(args)) => {
+            let profile = cli.profile()?;
+            let session_file = profile.session_file();
+            if session_file.exists() {
+                bail!(
+                    "Already logged in with the session in '{}', log out first",
+                    session_file.to_string_lossy()
+                );
+            }
+            if args.sso {
+                auth::login_sso(
+                    &profile.data_dir,
+                    &session_file,
+                    args.login.homeserver.clone(),
+                )
+                .await?;
+            } else {
+                auth::login(&profile.data_dir, &session_file, args.login.credentials()?).await?;
+            }
+            // A token left by a previous session doesn't match the new store.
+            SyncTokenStore::new(&profile.data_dir).clear().await?;
+        }
+        Some(Command::Run(login_args)) => return run_profiles(&cli, login_args).await,
+        None => return run_profiles(&cli, &cli.run).await,
+        Some(Command::Logout) => {
+            let profile = cli.profile()?;
+            let session_file = profile.session_file();
+            ensure_logged_in(&session_file)?;
+            auth::logout(&session_file).await?;
+            SyncTokenStore::new(&profile.data_dir).clear().await?;
+        }
+        Some(Command::Whoami) => {
+            let session_file = cli.profile()?.session_file();
+            ensure_logged_in(&session_file)?;
+            let session = auth::load_session(&session_file).await?;
+            println!("User:       {}", session.user_session.meta.user_id);
+            println!("Device:     {}", session.user_session.meta.device_id);
+            println!("Homeserver: {}", session.client_session.homeserver.trim());
+        }
+        Some(Command::Status) => {
+            let profile = cli.profile()?;
+            status(&cli, &profile.data_dir, &profile.session_file()).await?
+        }
+        Some(Command::Verify(args)) => return verify(&cli, &cli.profile()?, args).await,
+        Some(Command::Encryption(command)) => {
+            run_encryption_command(&cli.profile()?, command).await?
+        }
+        Some(Command::Keys(command)) => run_keys_command(&cli.profile()?, command).await?,
+        Some(Command::Devices(command)) => run_devices_command(&cli.profile()?, command).await?,
+        Some(Command::Profiles) => {
+            for profile in cli::list_profiles(&cli.base_data_dir()?)? {
+                println!(
+                    "{:<20} {}",
+                    profile.display_name(),
+                    if profile.session_file().exists() {
+                        "logged in"
+                    } else {
+                        "not logged in"
+                    }
+                );
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn ensure_logged_in(session_file: &Path) -> anyhow::Result<()> {
+    if !session_file.exists() {
+        return Err(not_logged_in(session_file));
+    }
+    Ok(())
+}
+
+fn not_logged_in(session_file: &Path) -> anyhow::Error {
+    anyhow::anyhow!(
+        "No session found in '{}', log in with `{CLIENT_NAME} login` first",
+        session_file.to_string_lossy()
+    )
+}
+
+/// Print the state of the data directory, session and config.
+async fn status(cli: &Cli, data_dir: &Path, session_file: &Path) -> anyhow::Result<()> {
+    println!("Data directory: {}", data_dir.to_string_lossy());
+
+    if session_file.exists() {
+        match auth::load_session(session_file).await {
+            Ok(session) => {
+                println!("Session:        {}", session.user_session.meta.user_id);
+                println!(
+                    "Store:          {}{}",
+                    session.client_session.db_path.to_string_lossy(),
+                    if session.client_session.db_path.exists() {
+                        ""
+                    } else {
+                        " (missing)"
+                    }
+                );
+                let sync_token = SyncTokenStore::new(data_dir).load().await;
+                println!(
+                    "Sync token:     {}",
+                    match sync_token {
+                        Ok(Some(_)) => "present".to_owned(),
+                        Ok(None) if session.sync_token.is_some() =>
+                            "present (in the session)".to_owned(),
+                        Ok(None) => "none".to_owned(),
+                        Err(error) => format!("unreadable ({error})"),
+                    }
+                );
+            }
+            Err(error) => println!("Session:        unreadable ({error})"),
+        }
+    } else {
+        println!("Session:        not logged in");
+    }
+
+    match Config::load(cli.config.as_deref(), data_dir) {
+        Ok(config) => println!(
+            "Config:         {} room(s), command prefix {:?}",
+            config.rooms.len(),
+            config.command_prefix
+        ),
+        Err(error) => println!("Config:         invalid ({error:#})"),
+    }
+
+    Ok(())
+}
+
+/// Restore the session of a profile for a one-off command, and sync once so
+/// the devices and keys are up to date.
+///
+/// The caller must hold the [`DataDirLock`] of the profile.
+///
+/// Returns the client and the settings to keep syncing. The sync token isn't
+/// persisted, so `run` still handles what arrives in the meantime.
+async fn restore_for_command(profile: &Profile) -> anyhow::Result<(Client, SyncSettings)> {
+    let session_file = profile.session_file();
+    ensure_logged_in(&session_file)?;
+
+    let (client, legacy_sync_token) = auth::restore_session(&session_file).await?;
+    let sync_token = SyncTokenStore::new(&profile.data_dir)
+        .load()
+        .await?
+        .or(legacy_sync_token);
+    tokio::spawn(auth::persist_session_changes(client.clone(), session_file));
+
+    let mut sync_settings =
+        SyncSettings::default().filter(FilterDefinition::with_lazy_loading().into());
+    if let Some(sync_token) = sync_token {
+        sync_settings = sync_settings.token(sync_token);
+    }
+    let response = client.sync_once(sync_settings.clone()).await?;
+
+    Ok((client, sync_settings.token(response.next_batch)))
+}
+
+/// Set up or restore the encryption of the account of a profile.
+async fn run_encryption_command(
+    profile: &Profile,
+    command: &EncryptionCommand,
+) -> anyhow::Result<()> {
+    let _lock = DataDirLock::acquire(&profile.data_dir)?;
+    let (client, _) = restore_for_command(profile).await?;
+
+    match command {
+        EncryptionCommand::Bootstrap(args) => {
+            let recovery_key = encryption::bootstrap(&client, args.password()?).await?;
+            println!("The key backup is set up, the recovery key is:\n\n    {recovery_key}\n");
+            println!(
+                "Store it somewhere safe: it restores the encryption of the account on a new device, with `{CLIENT_NAME} encryption recover`"
+            );
+        }
+        EncryptionCommand::Recover(args) => {
+            encryption::recover(&client, args.recovery_key()?).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Export or import the room keys of a profile.
+async fn run_keys_command(profile: &Profile, command: &KeysCommand) -> anyhow::Result<()> {
+    let session_file = profile.session_file();
+    ensure_logged_in(&session_file)?;
+    let _lock = DataDirLock::acquire(&profile.data_dir)?;
+    // The keys are in the store, no need to sync.
+    let (client, _) = auth::restore_session(&session_file).await?;
+
+    match command {
+        KeysCommand::Export(args) => {
+            let count = encryption::export_keys(&client, &args.file, args.passphrase()?).await?;
+            println!(
+                "Exported {count} room key(s) to '{}'",
+                args.file.to_string_lossy()
+            );
+        }
+        KeysCommand::Import(args) => {
+            let (imported, total) =
+                encryption::import_keys(&client, &args.file, args.passphrase()?).await?;
+            println!(
+                "Imported {imported} new room key(s) out of {total} from '{}'",
+                args.file.to_string_lossy()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// List the devices of users, or manage their trust and the bot's own.
+async fn run_devices_command(profile: &Profile, command: &DevicesCommand) -> anyhow::Result<()> {
+    let _lock = DataDirLock::acquire(&profile.data_dir)?;
+    let (client, _) = restore_for_command(profile).await?;
+    let own_user_id = client.user_id().ok_or(BotError::NotLoggedIn)?.to_owned();
+
+    let (args, trust) = match command {
+        DevicesCommand::List(args) => {
+            let user_id = match &args.user_id {
+                Some(user_id) => UserId::parse(user_id.as_str())?,
+                None => own_user_id,
+            };
+            let rows = devices::list(&client, &user_id).await?;
+            if rows.is_empty() {
+                println!("No known devices for {user_id}");
+            } else {
+                println!("{}", devices::format_table(&rows));
+            }
+            return Ok(());
+        }
+        DevicesCommand::Delete(args) => {
+            let device_ids = match args.stale_days {
+                Some(days) => {
+                    devices::stale(&client, Duration::from_secs(days * 24 * 60 * 60)).await?
+                }
+                None => args
+                    .device_ids
+                    .iter()
+                    .map(|device_id| device_id.as_str().into())
+                    .collect(),
+            };
+            if device_ids.is_empty() {
+                println!("No device to delete");
+                return Ok(());
+            }
+
+            for device_id in &device_ids {
+                println!(
+                    "{}{device_id}",
+                    if args.dry_run {
+                        "Would delete "
+                    } else {
+                        "Deleting "
+                    }
+                );
+            }
+            if !args.dry_run {
+                devices::delete(&client, &device_ids, args.password()?).await?;
+                println!("Deleted {} device(s)", device_ids.len());
+            }
+            return Ok(());
+        }
+        DevicesCommand::Trust(args) => (args, LocalTrust::Verified),
+        DevicesCommand::Blacklist(args) => (args, LocalTrust::BlackListed),
+        DevicesCommand::Untrust(args) => (args, LocalTrust::Unset),
+    };
+
+    let user_id = UserId::parse(args.user_id.as_str())?;
+    let device_id = <&DeviceId>::from(args.device_id.as_str());
+    devices::set_trust(&client, &user_id, device_id, trust).await?;
+    println!(
+        "{} device {device_id} of {user_id}",
+        match trust {
+            LocalTrust::Verified => "Trusted",
+            LocalTrust::BlackListed => "Blacklisted",
+            _ => "Reset the trust of",
+        }
+    );
+
+    Ok(())
+}
+
+/// Verify a user, one of their devices, or the other sessions of the bot, and
+/// wait until it is over.
+async fn verify(cli: &Cli, profile: &Profile, args: &VerifyArgs) -> anyhow::Result<ExitCode> {
+    let config = Arc::new(Config::load(cli.config.as_deref(), &profile.data_dir)?);
+
+    let shutdown = Arc::new(Shutdown::default());
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            if let Err(error) = shutdown.listen_for_signals().await {
+                warn!("Unable to listen to signals: {error:#}");
+            }
+        }
+    });
+    let reporter = Arc::new(ErrorReporter::new(false, shutdown.clone()));
+    let verifier = Arc::new(
+        Verifier::new(config, &profile.data_dir, reporter, shutdown.clone()).confirm_on_terminal(),
+    );
+
+    let _lock = DataDirLock::acquire(&profile.data_dir)?;
+    let (client, sync_settings) = restore_for_command(profile).await?;
+
+    let user_id = match &args.user_id {
+        Some(user_id) => UserId::parse(user_id.as_str())?,
+        None => client.user_id().ok_or(BotError::NotLoggedIn)?.to_owned(),
+    };
+    let device_id = args.device_id.as_deref().map(<&DeviceId>::from);
+
+    // The verification events come through the sync.
+    let sync = tokio::spawn({
+        let client = client.clone();
+        async move {
+            if let Err(error) = client.sync(sync_settings).await {
+                error!("Sync failed: {error}");
+            }
+        }
+    });
+
+    let verified = verification::request(&client, &verifier, &user_id, device_id).await;
+
+    sync.abort();
+    shutdown.wait_idle(SHUTDOWN_TIMEOUT).await;
+
+    if !verified? {
+        bail!("The verification with {user_id} didn't succeed");
+    }
+    println!("Verified {user_id}");
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Run the bot of each profile, until a shutdown is requested or one of them
+/// stops.
+async fn run_profiles(cli: &Cli, login_args: &LoginArgs) -> anyhow::Result<ExitCode> {
+    let profiles = cli.profiles()?;
+    let several = profiles.len() > 1;
+    if several && cli.config.is_some() {
+        bail!("--config can't be used with several profiles, each one uses its own config");
+    }
+
     info!("Starting {}", CLIENT_NAME);
 
-    // Stores in ~/Library/Application Support/oxybot
-    let data_dir = dirs::data_dir()
-        .expect("no data_dir directory found")
-        .join(CLIENT_NAME);
-    // The file where the session is persisted.
-    let session_file = data_dir.join("session");
+    let shutdown = Arc::new(Shutdown::default());
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            if let Err(error) = shutdown.listen_for_signals().await {
+                warn!("Unable to listen to signals: {error:#}");
+            }
+        }
+    });
+
+    let runs = profiles.iter().map(|profile| {
+        // The headless credentials are for a single account.
+        let login_args = (!several).then_some(login_args);
+        // Tag the logs with the profile when there are several.
+        let span = if several {
+            tracing::info_span!("profile", name = profile.display_name())
+        } else {
+            tracing::Span::none()
+        };
+
+        let shutdown = shutdown.clone();
+        async move {
+            let result = run(cli, login_args, profile, shutdown.clone()).await;
+            if let (true, Err(error)) = (several, &result) {
+                error!("{error:#}");
+            }
+            // Stop the other profiles too, so a supervisor notices.
+            shutdown.request();
+            result
+        }
+        .instrument(span)
+    });
+    let results = join_all(runs).await;
+
+    // The sync loops are over, only spawned tasks like verification flows can
+    // still be running.
+    let idle = shutdown.wait_idle(SHUTDOWN_TIMEOUT).await;
+
+    let mut exit_code = 0;
+    for result in results {
+        exit_code = exit_code.max(result?);
+    }
+    if exit_code == 0 && !idle {
+        exit_code = EXIT_INTERRUPTED;
+    }
+    if exit_code == 0 {
+        info!("Stopped");
+    }
+
+    Ok(ExitCode::from(exit_code))
+}
 
+/// Restore the session of a profile and run the bot until an error happens or
+/// it is asked to stop.
+///
+/// When there is no session yet, log in first if credentials were given.
+///
+/// Returns the exit code of the profile.
+async fn run(
+    cli: &Cli,
+    login_args: Option<&LoginArgs>,
+    profile: &Profile,
+    shutdown: Arc<Shutdown>,
+) -> anyhow::Result<u8> {
+    let data_dir = &profile.data_dir;
+    let session_file = profile.session_file();
+
+    let config = Arc::new(Config::load(cli.config.as_deref(), data_dir)?);
+    // Held until the profile stops.
+    let _lock = DataDirLock::acquire(data_dir)?;
+
+    let sync_tokens = SyncTokenStore::new(data_dir);
+
+    let credentials = match login_args {
+        Some(login_args) if !session_file.exists() => login_args.credentials()?,
+        _ => None,
+    };
     let (client, sync_token) = if session_file.exists() {
-        auth::restore_session(&session_file).await?
+        let (client, legacy_sync_token) = auth::restore_session(&session_file).await?;
+        (client, sync_tokens.load().await?.or(legacy_sync_token))
+    } else if let Some(credentials) = credentials {
+        let client = auth::login(data_dir, &session_file, Some(credentials)).await?;
+        sync_tokens.clear().await?;
+        (client, None)
     } else {
-        (auth::login(&data_dir, &session_file).await?, None)
+        return Err(not_logged_in(&session_file));
+    };
+
+    let reporter = Arc::new(ErrorReporter::new(config.error_replies, shutdown.clone()));
+    let verifier = Arc::new(Verifier::new(
+        config.clone(),
+        data_dir,
+        reporter.clone(),
+        shutdown.clone(),
+    ));
+    // Removed when the profile stops.
+    #[cfg(unix)]
+    let _control_socket = config
+        .verification
+        .control_socket
+        .then(|| control::ControlSocket::bind(data_dir, verifier.clone()))
+        .transpose()?;
+
+    let bot = Bot {
+        reporter,
+        verifier,
+        quotes: Arc::new(QuoteStore::load(data_dir, &config.quote_lists).await?),
+        health: Arc::new(Health::default()),
+        config,
+        sync_tokens,
+        shutdown,
+        session_file,
+        login_args,
     };
 
-    auth::setup_verification(&client).await;
+    match bot.sync(client, sync_token).await {
+        Ok(()) => Ok(0),
+        Err(error) if bot.health.report().state == HealthState::LoggedOut => {
+            let profile_arg = profile
+                .name
+                .as_ref()
+                .map(|name| format!(" --profile {name}"))
+                .unwrap_or_default();
+            error!("{error:#}");
+            error!(
+                "The session can't be recovered on its own, log in again with `{CLIENT_NAME}{profile_arg} logout` then `{CLIENT_NAME}{profile_arg} login`"
+            );
+            Ok(EXIT_LOGGED_OUT)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// What to do after a failed sync.
+enum Recovery {
+    /// Retry after the given delay.
+    Retry(Duration),
 
-    // Wait for the first sync response
-    println!("Wait for the first sync");
+    /// The session logged in again, a new client must be restored to use it.
+    Restore,
 
-    sync(client, sync_token, &session_file)
-        .await
-        .map_err(Into::into)
+    /// Retrying won't help.
+    GiveUp,
 }
 
-/// Setup the client to listen to new messages.
-async fn sync(
-    client: Client,
-    initial_sync_token: Option<String>,
-    session_file: &Path,
-) -> anyhow::Result<()> {
-    // https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members
-    let filter = FilterDefinition::with_lazy_loading();
-    let mut sync_settings = SyncSettings::default().filter(filter.into());
+/// Why the sync of a client stopped.
+enum Stop {
+    /// A shutdown was requested.
+    Shutdown,
+
+    /// The session logged in again, see [`Recovery::Restore`].
+    Restore,
+}
+
+/// The state of the running bot.
+struct Bot<'a> {
+    config: Arc<Config>,
+    quotes: Arc<QuoteStore>,
+    reporter: Arc<ErrorReporter>,
+    verifier: Arc<Verifier>,
+    health: Arc<Health>,
+    shutdown: Arc<Shutdown>,
+    sync_tokens: SyncTokenStore,
+    session_file: PathBuf,
+
+    /// Where to find the credentials to log in again, if anywhere.
+    login_args: Option<&'a LoginArgs>,
+}
+
+impl Bot<'_> {
+    /// Setup the client to listen to new messages, until a shutdown is
+    /// requested.
+    async fn sync(&self, client: Client, initial_sync_token: Option<String>) -> anyhow::Result<()> {
+        let mut client = client;
+        let mut sync_token = initial_sync_token;
+
+        loop {
+            // Keep the session file up to date with the tokens the client
+            // refreshes, as long as it is used.
+            let session_changes = tokio::spawn(auth::persist_session_changes(
+                client.clone(),
+                self.session_file.to_owned(),
+            ));
+
+            let result = self.sync_client(client, sync_token).await;
+            session_changes.abort();
+            // Make sure the task dropped its client.
+            let _ = session_changes.await;
+
+            match result? {
+                Stop::Shutdown => return Ok(()),
+                Stop::Restore => {
+                    // The previous client is dropped, so its crypto machine
+                    // doesn't share the store with the new one.
+                    let (restored, _) = auth::restore_session(&self.session_file).await?;
+                    client = restored;
+                    sync_token = self.sync_tokens.load().await?;
+                }
+            }
+        }
+    }
 
-    let ignore_past_messages = true;
-    if ignore_past_messages {
-        println!("Launching a first sync to ignore past messages…");
+    /// Sync `client` until a shutdown is requested or it has to be restored.
+    async fn sync_client(
+        &self,
+        client: Client,
+        initial_sync_token: Option<String>,
+    ) -> anyhow::Result<Stop> {
+        client.add_event_handler_context(self.shutdown.clone());
+        client.add_event_handler_context(self.reporter.clone());
+        client.add_event_handler_context(self.verifier.clone());
+
+        verification::setup(&client);
+
+        // Wait for the first sync response
+        println!("Wait for the first sync");
+
+        // https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members
+        let filter = FilterDefinition::with_lazy_loading();
+        let mut sync_settings = SyncSettings::default().filter(filter.into());
+
+        // Without a sync token, the first sync returns the recent history of every
+        // room, which we don't want to answer to.
+        let catch_up = Arc::new(CatchUp::new(&self.config.catch_up));
+        let catching_up = self.config.catch_up.enabled && initial_sync_token.is_some();
+
+        if catching_up {
+            println!("Launching a first sync to handle the messages missed while offline…");
+            self.register_handlers(&client, &catch_up);
+            catch_up.start();
+        } else {
+            println!("Launching a first sync to ignore past messages…");
+        }
 
         // This is not necessary when not using `sync_once`. The other sync methods get
         // the sync token from the store.
@@ -84,89 +673,269 @@ async fn sync(
         }
 
         loop {
-            match client.sync_once(sync_settings.clone()).await {
+            let sync_result = tokio::select! {
+                sync_result = client.sync_once(sync_settings.clone()) => sync_result,
+                // The token isn't persisted, so the interrupted response is
+                // received again on the next start.
+                () = self.shutdown.requested() => return Ok(Stop::Shutdown),
+            };
+
+            match sync_result {
                 Ok(response) => {
                     // This is the last time we need to provide this token, the sync method after
                     // will handle it on its own.
+                    // The handlers are done with the response at this point, so persisting the
+                    // token makes sure the missed messages are only handled once.
                     sync_settings = sync_settings.token(response.next_batch.clone());
-                    auth::persist_sync_token(session_file, response.next_batch).await?;
+                    self.sync_tokens.persist(response.next_batch).await?;
+                    self.health.sync_succeeded();
                     break;
                 }
-                Err(error) => {
-                    println!("An error occurred during initial sync: {error}");
-                    println!("Trying again…");
-                }
+                Err(error) => match self.recover(&client, &error).await {
+                    Recovery::Retry(delay) => {
+                        tokio::select! {
+                            () = tokio::time::sleep(delay) => {}
+                            () = self.shutdown.requested() => return Ok(Stop::Shutdown),
+                        }
+                    }
+                    Recovery::Restore => return Ok(Stop::Restore),
+                    Recovery::GiveUp => return Err(error.into()),
+                },
             }
         }
-    }
 
-    println!("The client is ready! Listening to new messages…");
+        if catching_up {
+            catch_up.finish();
+        } else {
+            // Now that we've synced, let's attach a handler for incoming room messages.
+            self.register_handlers(&client, &catch_up);
+        }
 
-    // Now that we've synced, let's attach a handler for incoming room messages.
-    client.add_event_handler(on_room_message);
+        println!("The client is ready! Listening to new messages…");
+
+        let timeline_watcher = TimelineWatcher::new(&self.config.timeline);
+        tokio::spawn(timeline::log_events(timeline_watcher.subscribe()));
+        timeline_watcher.start(&client).await?;
+
+        // This loops until a shutdown is requested, the client has to be
+        // restored, or an error that can't be retried happens.
+        let restore = AtomicBool::new(false);
+        let sync = client.sync_with_result_callback(sync_settings, |sync_result| {
+            let client = &client;
+            let catch_up = &catch_up;
+            let restore = &restore;
+            async move {
+                let response = match sync_result {
+                    Ok(response) => response,
+                    Err(error) => match self.recover(client, &error).await {
+                        Recovery::Retry(delay) => {
+                            tokio::time::sleep(delay).await;
+                            return Ok(LoopCtrl::Continue);
+                        }
+                        Recovery::Restore => {
+                            restore.store(true, Ordering::SeqCst);
+                            return Ok(LoopCtrl::Break);
+                        }
+                        Recovery::GiveUp => return Err(error),
+                    },
+                };
+                self.health.sync_succeeded();
+
+                // The token is only written from time to time, to restore our session
+                // without a full sync. With the catch-up, a token older than the
+                // messages answered would answer them again after a crash.
+                let persisted = if self.config.catch_up.enabled && catch_up.take_answered() {
+                    self.sync_tokens.persist(response.next_batch).await
+                } else {
+                    self.sync_tokens.set(response.next_batch).await
+                };
+                persisted.map_err(|err| Error::UnknownError(err.into()))?;
+
+                if self.shutdown.is_requested() {
+                    return Ok(LoopCtrl::Break);
+                }
+                Ok(LoopCtrl::Continue)
+            }
+        });
+        tokio::pin!(sync);
+
+        let result = tokio::select! {
+            result = &mut sync => result,
+            () = self.shutdown.requested() => {
+                if self.shutdown.is_idle() {
+                    // The sync is only waiting for the homeserver.
+                    Ok(())
+                } else {
+                    // The handlers run as part of the sync, let them finish with the
+                    // current response, after which the loop breaks.
+                    match tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut sync).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            warn!("The event handlers didn't finish in time");
+                            Ok(())
+                        }
+                    }
+                }
+            }
+        };
 
-    let room_id = room_id!("!EpdAGtDMTYZSR4VT5cG9:beeper.local");
-    let room = client.get_room(&room_id).unwrap();
-    let timeline = room.timeline().await;
-    timeline
-        .paginate_backwards(PaginationOptions::simple_request(10))
-        .await
-        .unwrap();
-    let (timeline_items, mut timeline_stream) = timeline.subscribe().await;
+        self.sync_tokens.flush().await?;
+        result?;
 
-    for event in timeline_items {
-        if let Some(e) = event.as_event() {
-            let c = e.content().to_owned();
-            if let Some(text) = c.as_message() {
-                let t = text.body();
-                println!("Event: {t:#?}");
-            }
+        if restore.load(Ordering::SeqCst) {
+            Ok(Stop::Restore)
+        } else {
+            Ok(Stop::Shutdown)
         }
     }
 
-    // println!("Initial timeline items: {timeline_items:#?}");
-    tokio::spawn(async move {
-        while let Some(diff) = timeline_stream.next().await {
-            diff.map(move |event| event.as_event().unwrap().to_owned())
-                .map(move |e| e.content().to_owned())
-                .map(|e| println!("Event: {e:#?}"));
+    /// Handle a failed sync: record it, then wait before retrying or log in
+    /// again.
+    async fn recover(&self, client: &Client, error: &Error) -> Recovery {
+        match Failure::of(error) {
+            Failure::Transient { retry_after } => {
+                let failures = self.health.sync_failed();
+                let delay = retry::backoff(failures).max(retry_after.unwrap_or_default());
+                warn!(
+                    "Sync failed {failures} time(s) in a row, retrying in {:.1}s: {error}",
+                    delay.as_secs_f64()
+                );
+                Recovery::Retry(delay)
+            }
+            Failure::LoggedOut { soft_logout } => {
+                self.health.set_stopped(HealthState::LoggedOut);
+                warn!(
+                    "The homeserver {} the access token, logging in again…",
+                    if soft_logout {
+                        "expired"
+                    } else {
+                        "invalidated"
+                    }
+                );
+
+                let credentials = match (self.login_args, client.user_id()) {
+                    (Some(login_args), Some(user_id)) => {
+                        login_args.credentials_for_session(client.homeserver().as_str(), user_id)
+                    }
+                    _ => Ok(None),
+                };
+                let reauthenticated = match credentials {
+                    Ok(credentials) => {
+                        auth::reauthenticate(client, &self.session_file, credentials, soft_logout)
+                            .await
+                    }
+                    Err(error) => Err(error),
+                };
+
+                match reauthenticated {
+                    Ok(Reauthenticated::Refreshed) => Recovery::Retry(Duration::ZERO),
+                    Ok(Reauthenticated::LoggedIn) => Recovery::Restore,
+                    Err(error) => {
+                        error!("Unable to log in again: {error:#}");
+                        Recovery::GiveUp
+                    }
+                }
+            }
+            Failure::Fatal => {
+                self.health.set_stopped(HealthState::Failed);
+                Recovery::GiveUp
+            }
         }
-    });
-
-    // This loops until we kill the program or an error happens.
-    client
-        .sync_with_result_callback(sync_settings, |sync_result| async move {
-            let response = sync_result?;
-
-            // We persist the token each time to be able to restore our session
-            auth::persist_sync_token(session_file, response.next_batch)
-                .await
-                .map_err(|err| Error::UnknownError(err.into()))?;
-
-            Ok(LoopCtrl::Continue)
-        })
-        .await?;
+    }
 
-    Ok(())
+    /// Attach the handlers for incoming room messages.
+    fn register_handlers(&self, client: &Client, catch_up: &Arc<CatchUp>) {
+        client.add_event_handler_context(Arc::new(MessageContext {
+            config: self.config.clone(),
+            commands: CommandRouter::new(&self.config.command_prefix)
+                .register(Hello)
+                .register(QuoteCommand::new(
+                    self.quotes.clone(),
+                    self.config.verification.admin_room.clone(),
+                ))
+                .register(HealthCommand::new(self.health.clone()))
+                .register(commands::DevicesCommand::new(
+                    self.config.verification.admin_room.clone(),
+                ))
+                .register(VerificationCommand::new(self.verifier.clone()))
+                .default_command("hello"),
+            responders: Responders::new(self.config.clone(), self.quotes.clone()),
+            catch_up: catch_up.clone(),
+        }));
+        client.add_event_handler(on_room_message);
+    }
 }
 
-fn get_fool_quote() -> &'static str {
-    let fool_quotes = [
-        "A fool thinks himself to be wise, but a wise man knows himself to be a fool.",
-        "The first principle is that you must not fool yourself and you are the easiest person to fool.",
-    ];
-    return &fool_quotes[rand::thread_rng().gen_range(0..fool_quotes.len())];
+/// What the room message handler needs, shared by all the rooms.
+struct MessageContext {
+    config: Arc<Config>,
+    commands: CommandRouter,
+    responders: Responders,
+    catch_up: Arc<CatchUp>,
 }
 
 /// Handle room messages.
-async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
-    // We only want to log text messages in joined rooms.
+async fn on_room_message(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    ctx: Ctx<Arc<MessageContext>>,
+    reporter: Ctx<Arc<ErrorReporter>>,
+    shutdown: Ctx<Arc<Shutdown>>,
+) {
+    let _in_flight = shutdown.track();
+
+    if let Err(error) = handle_room_message(&event, &room, &ctx).await {
+        reporter
+            .report_room_event("on_room_message", &room, &event.event_id, error)
+            .await;
+    }
+}
+
+async fn handle_room_message(
+    event: &OriginalSyncRoomMessageEvent,
+    room: &Room,
+    ctx: &MessageContext,
+) -> BotResult {
+    let MessageContext {
+        config,
+        commands,
+        responders,
+        catch_up,
+    } = ctx;
+
+    // We only want to handle messages in joined rooms.
     if room.state() != RoomState::Joined {
-        return;
+        return Ok(());
+    }
+
+    let client = room.client();
+    let user_id = client.user_id().ok_or(BotError::NotLoggedIn)?;
+
+    if event.sender == user_id {
+        return Ok(());
+    }
+
+    let room_config = config.room(room.room_id());
+    let run_responders = room_config.is_none_or(|room| room.responders);
+    let run_commands = room_config.is_none_or(|room| room.commands);
+
+    // Only the messages the bot answers count towards the catch-up limit.
+    let is_command = run_commands
+        && matches!(
+            &event.content.msgtype,
+            MessageType::Text(text_content) if commands.is_command(&text_content.body)
+        );
+    let answers = is_command || (run_responders && responders.matches(room, event));
+    if answers && !catch_up.should_handle(event.origin_server_ts) {
+        return Ok(());
+    }
+
+    if run_responders {
+        responders.handle(room, event).await?;
     }
 
     let MessageType::Text(text_content) = &event.content.msgtype else {
-        return;
+        return Ok(());
     };
 
     let room_name = match room.display_name().await {
@@ -178,30 +947,13 @@ async fn on_room_message(event: OriginalSyncRoomMessageEvent, room: Room) {
         }
     };
 
-    let client = room.client();
-    let user_id = client.user_id().unwrap();
-
-    let sent_by_me = event.sender == user_id;
-
     info!("[{room_name}] {}: {}", event.sender, text_content.body);
 
-    if sent_by_me {
-        return;
+    if run_commands {
+        commands
+            .handle(room, &event.sender, &text_content.body)
+            .await?;
     }
 
-    if text_content.body.starts_with("!oxy") {
-        let user = client.get_profile(user_id).await.unwrap();
-        let display_name = user.displayname.unwrap_or("Stranger".to_string());
-
-        let message =
-            RoomMessageEventContent::text_plain("Well hello there ".to_string() + &display_name);
-        room.send(message).await.unwrap();
-    }
-
-    if event.sender
-        == user_id!("@signal_b0431c07-a3b8-44e2-8022-5fde36a5c4a5:beeper.local").to_owned()
-    {
-        let message = RoomMessageEventContent::text_plain(get_fool_quote().to_string());
-        room.send(message).await.unwrap();
-    }
+    Ok(())
 }
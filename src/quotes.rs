@@ -0,0 +1,303 @@
+use log::info;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedUserId};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tokio::{fs, sync::Mutex};
+
+/// The name of the quote database in the data directory.
+pub const QUOTES_FILE_NAME: &str = "quotes.json";
+
+/// The quotes the database is seeded with, tagged `fool`.
+const FOOL_QUOTES: &[&str] = &[
+    "A fool thinks himself to be wise, but a wise man knows himself to be a fool.",
+    "The first principle is that you must not fool yourself and you are the easiest person to fool.",
+];
+
+/// A quote curated by the rooms.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quote {
+    /// The ID used to refer to the quote in commands.
+    pub id: u64,
+
+    /// The quote itself.
+    pub text: String,
+
+    /// The tags of the quote, lowercase.
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// The user who added the quote, `None` for the seeded quotes.
+    pub added_by: Option<OwnedUserId>,
+
+    /// When the quote was added.
+    pub added_at: MilliSecondsSinceUnixEpoch,
+}
+
+impl Quote {
+    /// Whether the quote has the given tag.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+}
+
+/// The content of the quote database file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QuoteFile {
+    next_id: u64,
+    quotes: Vec<Quote>,
+
+    /// The `quote_lists` of the config already imported, so they are only
+    /// imported once.
+    #[serde(default)]
+    imported_lists: Vec<String>,
+}
+
+/// A persistent database of quotes, stored as JSON next to the session.
+#[derive(Debug)]
+pub struct QuoteStore {
+    path: PathBuf,
+    file: Mutex<QuoteFile>,
+}
+
+impl QuoteStore {
+    /// Load the database from the data directory, or create it with the
+    /// `fool` quotes if it doesn't exist yet.
+    ///
+    /// The quote lists of older configs that weren't imported yet are added,
+    /// tagged with the name of their list.
+    pub async fn load(
+        data_dir: &Path,
+        quote_lists: &BTreeMap<String, Vec<String>>,
+    ) -> anyhow::Result<Self> {
+        let path = data_dir.join(QUOTES_FILE_NAME);
+
+        let (mut file, mut changed) = if path.exists() {
+            let serialized = fs::read_to_string(&path).await?;
+            (serde_json::from_str(&serialized)?, false)
+        } else {
+            let mut file = QuoteFile::default();
+            for text in FOOL_QUOTES {
+                file.push(text.to_string(), vec!["fool".to_owned()], None);
+            }
+            (file, true)
+        };
+
+        for (name, quotes) in quote_lists {
+            if file.imported_lists.contains(name) {
+                continue;
+            }
+            for text in quotes {
+                file.push(text.clone(), vec![name.clone()], None);
+            }
+            file.imported_lists.push(name.clone());
+            info!("Imported {} quote(s) of the list `{name}`", quotes.len());
+            changed = true;
+        }
+
+        if changed {
+            file.save(&path).await?;
+        }
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Add a quote and return it.
+    pub async fn add(
+        &self,
+        text: String,
+        tags: Vec<String>,
+        added_by: OwnedUserId,
+    ) -> anyhow::Result<Quote> {
+        let mut file = self.file.lock().await;
+        let quote = file.push(text, tags, Some(added_by));
+        file.save(&self.path).await?;
+        Ok(quote)
+    }
+
+    /// Get a quote by ID.
+    pub async fn get(&self, id: u64) -> Option<Quote> {
+        let file = self.file.lock().await;
+        file.quotes.iter().find(|quote| quote.id == id).cloned()
+    }
+
+    /// Remove a quote and return it, if it exists.
+    pub async fn remove(&self, id: u64) -> anyhow::Result<Option<Quote>> {
+        let mut file = self.file.lock().await;
+        let Some(index) = file.quotes.iter().position(|quote| quote.id == id) else {
+            return Ok(None);
+        };
+        let quote = file.quotes.remove(index);
+        file.save(&self.path).await?;
+        Ok(Some(quote))
+    }
+
+    /// A random quote, with the given tag if any.
+    pub async fn random(&self, tag: Option<&str>) -> Option<Quote> {
+        let file = self.file.lock().await;
+        let candidates: Vec<_> = file
+            .quotes
+            .iter()
+            .filter(|quote| tag.is_none_or(|tag| quote.has_tag(tag)))
+            .collect();
+        candidates
+            .choose(&mut rand::thread_rng())
+            .map(|quote| (*quote).clone())
+    }
+
+    /// The quotes containing the given text, case-insensitively.
+    pub async fn search(&self, text: &str) -> Vec<Quote> {
+        let text = text.to_lowercase();
+        let file = self.file.lock().await;
+        file.quotes
+            .iter()
+            .filter(|quote| quote.text.to_lowercase().contains(&text))
+            .cloned()
+            .collect()
+    }
+}
+
+impl QuoteFile {
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        // Write to a temporary file first so a crash doesn't lose the quotes.
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?).await?;
+        fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    fn push(&mut self, text: String, tags: Vec<String>, added_by: Option<OwnedUserId>) -> Quote {
+        self.next_id += 1;
+        let quote = Quote {
+            id: self.next_id,
+            text,
+            tags: tags.into_iter().map(|tag| tag.to_lowercase()).collect(),
+            added_by,
+            added_at: MilliSecondsSinceUnixEpoch::now(),
+        };
+        self.quotes.push(quote.clone());
+        quote
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::owned_user_id;
+
+    use super::*;
+
+    fn lists(lists: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        lists
+            .iter()
+            .map(|(name, quotes)| {
+                let quotes = quotes.iter().map(ToString::to_string).collect();
+                (name.to_string(), quotes)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn add_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = QuoteStore::load(dir.path(), &BTreeMap::new())
+            .await
+            .unwrap();
+
+        let quote = store
+            .add(
+                "Don't panic.".to_owned(),
+                vec!["Wisdom".to_owned()],
+                owned_user_id!("@alice:example.org"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(quote.id, FOOL_QUOTES.len() as u64 + 1);
+        assert_eq!(quote.tags, ["wisdom"]);
+        assert_eq!(store.get(quote.id).await.unwrap().text, "Don't panic.");
+
+        // The quote is saved.
+        let reloaded = QuoteStore::load(dir.path(), &BTreeMap::new())
+            .await
+            .unwrap();
+        assert_eq!(reloaded.get(quote.id).await.unwrap().text, "Don't panic.");
+
+        assert_eq!(store.remove(quote.id).await.unwrap().unwrap().id, quote.id);
+        assert!(store.remove(quote.id).await.unwrap().is_none());
+        assert!(store.get(quote.id).await.is_none());
+
+        // IDs are not reused.
+        let next = store
+            .add(
+                "Again.".to_owned(),
+                vec![],
+                owned_user_id!("@alice:example.org"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(next.id, quote.id + 1);
+    }
+
+    #[tokio::test]
+    async fn random_by_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = QuoteStore::load(dir.path(), &lists(&[("wisdom", &["Know thyself."])]))
+            .await
+            .unwrap();
+
+        for _ in 0..10 {
+            let quote = store.random(Some("WISDOM")).await.unwrap();
+            assert_eq!(quote.text, "Know thyself.");
+            assert!(store.random(Some("fool")).await.unwrap().has_tag("fool"));
+            assert!(store.random(None).await.is_some());
+        }
+        assert!(store.random(Some("unknown")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn search() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = QuoteStore::load(dir.path(), &BTreeMap::new())
+            .await
+            .unwrap();
+
+        assert_eq!(store.search("FOOL").await.len(), FOOL_QUOTES.len());
+        let found = store.search("easiest person").await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].text, FOOL_QUOTES[1]);
+        assert!(store.search("nowhere").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn quote_lists_are_imported_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let quote_lists = lists(&[("wisdom", &["Know thyself.", "Don't panic."])]);
+
+        let store = QuoteStore::load(dir.path(), &quote_lists).await.unwrap();
+        let imported = store.search("").await;
+        assert_eq!(imported.len(), FOOL_QUOTES.len() + 2);
+        let wisdom: Vec<_> = imported
+            .iter()
+            .filter(|quote| quote.has_tag("wisdom"))
+            .collect();
+        assert_eq!(wisdom.len(), 2);
+        assert!(wisdom.iter().all(|quote| quote.added_by.is_none()));
+
+        // A deleted quote of the list doesn't come back.
+        store.remove(wisdom[0].id).await.unwrap();
+        drop(store);
+        let store = QuoteStore::load(dir.path(), &quote_lists).await.unwrap();
+        assert_eq!(store.search("").await.len(), FOOL_QUOTES.len() + 1);
+
+        // A new list is still imported.
+        let quote_lists = lists(&[("wisdom", &["Know thyself."]), ("fun", &["Ha."])]);
+        let store = QuoteStore::load(dir.path(), &quote_lists).await.unwrap();
+        assert_eq!(store.search("").await.len(), FOOL_QUOTES.len() + 2);
+        assert!(store.random(Some("fun")).await.is_some());
+    }
+}
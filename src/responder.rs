@@ -0,0 +1,304 @@
+use matrix_sdk::ruma::{
+    events::{
+        reaction::ReactionEventContent,
+        relation::Annotation,
+        room::message::{OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+    },
+    OwnedRoomId, RoomId, UserId,
+};
+use matrix_sdk::Room;
+use rand::Rng;
+use regex::Captures;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Config, ResponderRule, Response},
+    error::BotResult,
+    quotes::QuoteStore,
+};
+
+/// Runs the responder rules of the config on incoming messages.
+pub struct Responders {
+    config: Arc<Config>,
+    quotes: Arc<QuoteStore>,
+
+    /// When each rule last responded in each room, to apply the cooldowns.
+    last_responses: Mutex<HashMap<(usize, OwnedRoomId), Instant>>,
+}
+
+impl Responders {
+    pub fn new(config: Arc<Config>, quotes: Arc<QuoteStore>) -> Self {
+        Self {
+            config,
+            quotes,
+            last_responses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Respond to a message with every rule that matches it.
+    pub async fn handle(&self, room: &Room, event: &OriginalSyncRoomMessageEvent) -> BotResult {
+        let body = event.content.body();
+
+        for (index, rule) in self.config.responders.iter().enumerate() {
+            let Some(captures) = rule_matches(rule, room, event, body) else {
+                continue;
+            };
+
+            if rule.probability < 1.0 && !rand::thread_rng().gen_bool(rule.probability) {
+                continue;
+            }
+
+            if !self.start_cooldown(index, rule, room) {
+                continue;
+            }
+
+            self.respond(rule, room, event, captures).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a rule matches the message, before the probability and the
+    /// cooldown are taken into account.
+    pub fn matches(&self, room: &Room, event: &OriginalSyncRoomMessageEvent) -> bool {
+        let body = event.content.body();
+        self.config
+            .responders
+            .iter()
+            .any(|rule| rule_matches(rule, room, event, body).is_some())
+    }
+
+    /// Check the cooldown of the rule in the room, and restart it if it is
+    /// over.
+    ///
+    /// Returns whether the rule can respond.
+    fn start_cooldown(&self, index: usize, rule: &ResponderRule, room: &Room) -> bool {
+        start_cooldown(
+            &mut self.last_responses.lock().unwrap(),
+            (index, room.room_id().to_owned()),
+            Duration::from_secs(rule.cooldown_secs),
+            Instant::now(),
+        )
+    }
+
+    async fn respond(
+        &self,
+        rule: &ResponderRule,
+        room: &Room,
+        event: &OriginalSyncRoomMessageEvent,
+        captures: Option<Captures<'_>>,
+    ) -> BotResult {
+        match &rule.respond {
+            Response::Quotes(tag) => {
+                if let Some(quote) = self.quotes.random(Some(tag)).await {
+                    room.send(RoomMessageEventContent::text_plain(quote.text))
+                        .await?;
+                }
+            }
+            Response::Template(template) => {
+                let message = render_template(
+                    template,
+                    &event.sender,
+                    room.room_id(),
+                    event.content.body(),
+                    captures.as_ref(),
+                );
+                room.send(RoomMessageEventContent::text_plain(message))
+                    .await?;
+            }
+            Response::Reaction(key) => {
+                let annotation = Annotation::new(event.event_id.clone(), key.clone());
+                room.send(ReactionEventContent::new(annotation)).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check whether the rule matches the message.
+///
+/// Returns `None` if it doesn't match, and the captures of the body regex if
+/// it does.
+fn rule_matches<'a>(
+    rule: &ResponderRule,
+    room: &Room,
+    event: &OriginalSyncRoomMessageEvent,
+    body: &'a str,
+) -> Option<Option<Captures<'a>>> {
+    if rule
+        .sender
+        .as_ref()
+        .is_some_and(|sender| *sender != event.sender)
+    {
+        return None;
+    }
+    if rule
+        .room
+        .as_deref()
+        .is_some_and(|room_id| room_id != room.room_id())
+    {
+        return None;
+    }
+    if let Some(msgtype) = &rule.msgtype {
+        let actual = event.content.msgtype();
+        if actual != msgtype && actual.strip_prefix("m.") != Some(msgtype.as_str()) {
+            return None;
+        }
+    }
+
+    match &rule.body {
+        Some(regex) => regex.captures(body).map(Some),
+        None => Some(None),
+    }
+}
+
+/// Check the cooldown stored under `key`, and restart it at `now` if it is
+/// over.
+fn start_cooldown(
+    last_responses: &mut HashMap<(usize, OwnedRoomId), Instant>,
+    key: (usize, OwnedRoomId),
+    cooldown: Duration,
+    now: Instant,
+) -> bool {
+    if let Some(last) = last_responses.get(&key) {
+        if now.duration_since(*last) < cooldown {
+            return false;
+        }
+    }
+
+    last_responses.insert(key, now);
+    true
+}
+
+/// Replace the placeholders of a template.
+///
+/// Unknown placeholders are kept as-is.
+fn render_template(
+    template: &str,
+    sender: &UserId,
+    room_id: &RoomId,
+    body: &str,
+    captures: Option<&Captures<'_>>,
+) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[1..end];
+
+        let value = match name {
+            "sender" => Some(sender.as_str()),
+            "room" => Some(room_id.as_str()),
+            "body" => Some(body),
+            _ => captures.and_then(|captures| {
+                let capture = match name.parse::<usize>() {
+                    Ok(index) => captures.get(index),
+                    Err(_) => captures.name(name),
+                };
+                capture.map(|capture| capture.as_str())
+            }),
+        };
+
+        match value {
+            Some(value) => output.push_str(value),
+            None => output.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{room_id, user_id};
+    use regex::Regex;
+
+    use super::*;
+
+    fn render(template: &str, captures: Option<&Captures<'_>>) -> String {
+        render_template(
+            template,
+            user_id!("@alice:example.org"),
+            room_id!("!room:example.org"),
+            "hello world",
+            captures,
+        )
+    }
+
+    #[test]
+    fn template_placeholders() {
+        assert_eq!(
+            render("{sender} said {body} in {room}", None),
+            "@alice:example.org said hello world in !room:example.org"
+        );
+    }
+
+    #[test]
+    fn template_captures() {
+        let regex = Regex::new(r"(?P<greeting>\w+) (\w+)").unwrap();
+        let captures = regex.captures("hello world").unwrap();
+        assert_eq!(
+            render("{greeting}, {2}! ({0})", Some(&captures)),
+            "hello, world! (hello world)"
+        );
+    }
+
+    #[test]
+    fn template_unknown_placeholders_are_kept() {
+        assert_eq!(render("{nope} {3}", None), "{nope} {3}");
+        assert_eq!(render("unclosed {sender", None), "unclosed {sender");
+    }
+
+    #[test]
+    fn cooldown() {
+        let mut last_responses = HashMap::new();
+        let room_id = room_id!("!room:example.org").to_owned();
+        let cooldown = Duration::from_secs(60);
+        let start = Instant::now();
+
+        assert!(start_cooldown(
+            &mut last_responses,
+            (0, room_id.clone()),
+            cooldown,
+            start
+        ));
+        assert!(!start_cooldown(
+            &mut last_responses,
+            (0, room_id.clone()),
+            cooldown,
+            start + Duration::from_secs(59)
+        ));
+        // Other rules and rooms have their own cooldown.
+        assert!(start_cooldown(
+            &mut last_responses,
+            (1, room_id.clone()),
+            cooldown,
+            start
+        ));
+        assert!(start_cooldown(
+            &mut last_responses,
+            (0, room_id!("!other:example.org").to_owned()),
+            cooldown,
+            start
+        ));
+        assert!(start_cooldown(
+            &mut last_responses,
+            (0, room_id),
+            cooldown,
+            start + cooldown
+        ));
+    }
+}
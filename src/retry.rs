@@ -0,0 +1,98 @@
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use rand::Rng;
+use std::time::Duration;
+
+/// The delay before the first retry.
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay between two retries.
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// What a failed request means for the requests that follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The homeserver can't be reached or is overloaded, retrying later
+    /// should work.
+    Transient {
+        /// How long the homeserver asked us to wait, if it did.
+        retry_after: Option<Duration>,
+    },
+
+    /// The access token is no longer valid, the session has to log in again.
+    LoggedOut {
+        /// Whether the device and its keys can be kept when logging in again.
+        soft_logout: bool,
+    },
+
+    /// Retrying won't help.
+    Fatal,
+}
+
+impl Failure {
+    /// Classify the error of a request to the homeserver.
+    pub fn of(error: &matrix_sdk::Error) -> Self {
+        let matrix_sdk::Error::Http(http_error) = error else {
+            // Errors of the stores or the crypto machine.
+            return Self::Fatal;
+        };
+
+        let Some(api_error) = http_error.as_client_api_error() else {
+            // Network errors, timeouts, or responses that aren't Matrix errors.
+            return Self::Transient { retry_after: None };
+        };
+
+        match http_error.client_api_error_kind() {
+            Some(ErrorKind::UnknownToken { soft_logout }) => Self::LoggedOut {
+                soft_logout: *soft_logout,
+            },
+            Some(ErrorKind::LimitExceeded { retry_after_ms }) => Self::Transient {
+                retry_after: *retry_after_ms,
+            },
+            _ if api_error.status_code.is_server_error() => Self::Transient { retry_after: None },
+            _ => Self::Fatal,
+        }
+    }
+}
+
+/// The delay before retrying after `failures` consecutive failures.
+///
+/// It doubles with each failure up to a maximum, with a random part so that
+/// clients failing together don't retry together.
+pub fn backoff(failures: u32) -> Duration {
+    let ceiling = INITIAL_DELAY
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_DELAY);
+
+    let half = ceiling / 2;
+    half + half.mul_f64(rand::thread_rng().gen())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_between(delay: Duration, ceiling: Duration) {
+        assert!(
+            delay >= ceiling / 2 && delay <= ceiling,
+            "{delay:?} is not between {:?} and {ceiling:?}",
+            ceiling / 2
+        );
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        for _ in 0..100 {
+            assert_between(backoff(0), INITIAL_DELAY);
+            assert_between(backoff(1), INITIAL_DELAY);
+            assert_between(backoff(2), INITIAL_DELAY * 2);
+            assert_between(backoff(5), INITIAL_DELAY * 16);
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for failures in [10, 31, 32, 1000, u32::MAX] {
+            assert_between(backoff(failures), MAX_DELAY);
+        }
+    }
+}
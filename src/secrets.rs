@@ -0,0 +1,442 @@
+use anyhow::{anyhow, bail, Context};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// The keyring service under which the session keys are stored.
+const KEYRING_SERVICE: &str = "oxybot";
+
+/// The environment variables to derive the key from a passphrase, when no
+/// keyring is available.
+const PASSPHRASE_ENV: &str = "OXYBOT_SESSION_PASSPHRASE";
+const PASSPHRASE_FILE_ENV: &str = "OXYBOT_SESSION_PASSPHRASE_FILE";
+
+/// The keys already fetched or derived, by file, so the keyring isn't queried
+/// and the passphrase isn't derived on every write.
+static KEYS: Mutex<Option<HashMap<PathBuf, CachedKey>>> = Mutex::new(None);
+
+#[derive(Clone)]
+struct CachedKey {
+    source: KeySource,
+    key: [u8; 32],
+}
+
+/// Where the key of an encrypted file comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum KeySource {
+    /// A random key stored in the Secret Service or the kernel keyring.
+    Keyring,
+
+    /// A key derived with argon2 from a passphrase given in the environment.
+    Passphrase { salt: String },
+}
+
+/// The content of an encrypted file.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedFile {
+    key_source: KeySource,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Read a file written with [`write`].
+///
+/// Plaintext files from older versions are encrypted in place.
+pub async fn read(path: &Path) -> anyhow::Result<String> {
+    let content = fs::read_to_string(path).await?;
+
+    let Ok(encrypted) = serde_json::from_str::<EncryptedFile>(&content) else {
+        // A truncated or damaged file must not be mistaken for a plaintext one.
+        if serde_json::from_str::<serde_json::Value>(&content).is_err() {
+            bail!("'{}' is corrupt", path.to_string_lossy());
+        }
+
+        info!("Encrypting the plaintext file '{}'", path.to_string_lossy());
+        // Don't keep the plaintext as the backup.
+        write_atomically(path, encrypt(path, &content).await?.as_bytes()).await?;
+        return Ok(content);
+    };
+
+    decrypt(path, path, &encrypted).await
+}
+
+/// Read the backup of a file written with [`write`].
+pub async fn read_backup(path: &Path) -> anyhow::Result<String> {
+    let backup = backup_path(path);
+    let content = fs::read_to_string(&backup).await?;
+    let encrypted = serde_json::from_str::<EncryptedFile>(&content)
+        .with_context(|| format!("'{}' is corrupt", backup.to_string_lossy()))?;
+
+    // The backup is encrypted with the key of the file.
+    decrypt(path, &backup, &encrypted).await
+}
+
+async fn decrypt(
+    key_path: &Path,
+    path: &Path,
+    encrypted: &EncryptedFile,
+) -> anyhow::Result<String> {
+    let cached = key_for_reading(key_path, &encrypted.key_source).await?;
+    let nonce = BASE64.decode(&encrypted.nonce)?;
+    let ciphertext = BASE64.decode(&encrypted.ciphertext)?;
+
+    let plaintext = XChaCha20Poly1305::new(Key::from_slice(&cached.key))
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| {
+            anyhow!(
+                "Unable to decrypt '{}', the key or passphrase is wrong",
+                path.to_string_lossy()
+            )
+        })?;
+
+    Ok(String::from_utf8(plaintext)?)
+}
+
+/// Encrypt and write a file.
+///
+/// The file is replaced atomically, and the previous version is kept as the
+/// backup, see [`backup_path`].
+pub async fn write(path: &Path, plaintext: &str) -> anyhow::Result<()> {
+    let encrypted = encrypt(path, plaintext).await?;
+
+    if path.exists() {
+        // Like the file itself, a crash must not leave a partial backup.
+        let previous = fs::read(path).await?;
+        write_atomically(&backup_path(path), &previous).await?;
+    }
+    write_atomically(path, encrypted.as_bytes()).await
+}
+
+/// Replace a file with its backup, as is.
+pub async fn restore_backup(path: &Path) -> anyhow::Result<()> {
+    let backup = fs::read(backup_path(path)).await?;
+    write_atomically(path, &backup).await
+}
+
+/// The path of the backup of a file, its previous version.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    backup.into()
+}
+
+/// Write a file that is only readable by the current user, so that a crash
+/// leaves either the old or the new content.
+pub(crate) async fn write_atomically(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp_path).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&tmp_path, path).await?;
+
+    // Persist the rename itself.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir).await?.sync_all().await?;
+    }
+
+    Ok(())
+}
+
+async fn encrypt(path: &Path, plaintext: &str) -> anyhow::Result<String> {
+    let cached = key_for_writing(path).await?;
+
+    let mut nonce = [0u8; 24];
+    rand::thread_rng().fill(&mut nonce);
+    let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&cached.key))
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| anyhow!("Unable to encrypt '{}'", path.to_string_lossy()))?;
+
+    let encrypted = EncryptedFile {
+        key_source: cached.source,
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    };
+    Ok(serde_json::to_string(&encrypted)?)
+}
+
+/// Forget the key of a file, removing it from the keyring.
+pub async fn forget(path: &Path) -> anyhow::Result<()> {
+    let cached = cache().as_mut().and_then(|keys| keys.remove(path));
+    if cached.is_some_and(|cached| cached.source != KeySource::Keyring) {
+        return Ok(());
+    }
+
+    let account = keyring_account(path);
+    tokio::task::spawn_blocking(move || {
+        match keyring::Entry::new(KEYRING_SERVICE, &account)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(error) => Err(error),
+        }
+    })
+    .await??;
+
+    Ok(())
+}
+
+fn cache() -> std::sync::MutexGuard<'static, Option<HashMap<PathBuf, CachedKey>>> {
+    KEYS.lock().unwrap()
+}
+
+fn cached(path: &Path) -> Option<CachedKey> {
+    cache().as_ref()?.get(path).cloned()
+}
+
+fn store_in_cache(path: &Path, key: CachedKey) -> CachedKey {
+    cache()
+        .get_or_insert_with(HashMap::new)
+        .insert(path.to_owned(), key.clone());
+    key
+}
+
+async fn key_for_reading(path: &Path, source: &KeySource) -> anyhow::Result<CachedKey> {
+    if let Some(cached) = cached(path).filter(|cached| cached.source == *source) {
+        return Ok(cached);
+    }
+
+    let key = match source {
+        KeySource::Keyring => {
+            let account = keyring_account(path);
+            tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+                let entry = keyring::Entry::new(KEYRING_SERVICE, &account)?;
+                let key = entry.get_password().with_context(|| {
+                    format!("No key for '{account}' in the keyring, the session can't be decrypted")
+                })?;
+                decode_key(&key)
+            })
+            .await??
+        }
+        KeySource::Passphrase { salt } => {
+            let Some(passphrase) = passphrase()? else {
+                bail!(
+                    "'{}' is encrypted with a passphrase, set {PASSPHRASE_ENV} or {PASSPHRASE_FILE_ENV}",
+                    path.to_string_lossy()
+                );
+            };
+            derive_key(passphrase, BASE64.decode(salt)?).await?
+        }
+    };
+
+    Ok(store_in_cache(
+        path,
+        CachedKey {
+            source: source.clone(),
+            key,
+        },
+    ))
+}
+
+/// Get the key to write a file.
+///
+/// A passphrase from the environment takes precedence over the keyring.
+async fn key_for_writing(path: &Path) -> anyhow::Result<CachedKey> {
+    if let Some(cached) = cached(path) {
+        return Ok(cached);
+    }
+
+    if let Some(passphrase) = passphrase()? {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill(&mut salt);
+        let key = derive_key(passphrase, salt.to_vec()).await?;
+        return Ok(store_in_cache(
+            path,
+            CachedKey {
+                source: KeySource::Passphrase {
+                    salt: BASE64.encode(salt),
+                },
+                key,
+            },
+        ));
+    }
+
+    let account = keyring_account(path);
+    let key = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, &account)?;
+        match entry.get_password() {
+            Ok(key) => decode_key(&key),
+            Err(keyring::Error::NoEntry) => {
+                let mut key = [0u8; 32];
+                rand::thread_rng().fill(&mut key);
+                entry.set_password(&BASE64.encode(key))?;
+                Ok(key)
+            }
+            Err(error) => Err(error.into()),
+        }
+    })
+    .await?
+    .map_err(|error| {
+        warn!("The keyring is not available: {error:#}");
+        anyhow!("No keyring available to encrypt the session, set {PASSPHRASE_ENV} or {PASSPHRASE_FILE_ENV}")
+    })?;
+
+    Ok(store_in_cache(
+        path,
+        CachedKey {
+            source: KeySource::Keyring,
+            key,
+        },
+    ))
+}
+
+/// The keyring account of a file, its absolute path so that profiles don't
+/// share keys.
+fn keyring_account(path: &Path) -> String {
+    let path = match std::env::current_dir() {
+        Ok(current_dir) if path.is_relative() => current_dir.join(path),
+        _ => path.to_owned(),
+    };
+    path.to_string_lossy().into_owned()
+}
+
+fn decode_key(encoded: &str) -> anyhow::Result<[u8; 32]> {
+    BASE64
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| anyhow!("The key in the keyring has the wrong length"))
+}
+
+fn passphrase() -> anyhow::Result<Option<String>> {
+    if let Ok(file) = std::env::var(PASSPHRASE_FILE_ENV) {
+        let passphrase = std::fs::read_to_string(&file)
+            .with_context(|| format!("Unable to read {PASSPHRASE_FILE_ENV} '{file}'"))?;
+        return Ok(Some(passphrase.trim_end_matches(['\r', '\n']).to_owned()));
+    }
+    Ok(std::env::var(PASSPHRASE_ENV).ok())
+}
+
+async fn derive_key(passphrase: String, salt: Vec<u8>) -> anyhow::Result<[u8; 32]> {
+    // Deriving is slow on purpose, keep it off the runtime threads.
+    tokio::task::spawn_blocking(move || {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|error| anyhow!("Unable to derive the key from the passphrase: {error}"))?;
+        Ok(key)
+    })
+    .await?
+}
+
+/// Use a fixed key for the file in tests, without a keyring or passphrase.
+#[cfg(test)]
+pub fn use_test_key(path: &Path) {
+    store_in_cache(
+        path,
+        CachedKey {
+            source: KeySource::Passphrase {
+                salt: BASE64.encode([0u8; 16]),
+            },
+            key: [7; 32],
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        use_test_key(&path);
+
+        write(&path, r#"{"token":"secret"}"#).await.unwrap();
+
+        let on_disk = std::fs::read_to_string(&path).unwrap();
+        assert!(!on_disk.contains("secret"));
+        assert_eq!(read(&path).await.unwrap(), r#"{"token":"secret"}"#);
+    }
+
+    #[tokio::test]
+    async fn wrong_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        use_test_key(&path);
+        write(&path, "{}").await.unwrap();
+
+        store_in_cache(
+            &path,
+            CachedKey {
+                source: KeySource::Passphrase {
+                    salt: BASE64.encode([0u8; 16]),
+                },
+                key: [8; 32],
+            },
+        );
+        assert!(read(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_encrypted_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        use_test_key(&path);
+        std::fs::write(&path, r#"{"token":"secret"}"#).unwrap();
+
+        assert_eq!(read(&path).await.unwrap(), r#"{"token":"secret"}"#);
+
+        let on_disk = std::fs::read_to_string(&path).unwrap();
+        assert!(serde_json::from_str::<EncryptedFile>(&on_disk).is_ok());
+        assert_eq!(read(&path).await.unwrap(), r#"{"token":"secret"}"#);
+    }
+
+    #[tokio::test]
+    async fn backup_is_the_previous_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        use_test_key(&path);
+
+        write(&path, "{\"version\":1}").await.unwrap();
+        write(&path, "{\"version\":2}").await.unwrap();
+
+        assert_eq!(read(&path).await.unwrap(), "{\"version\":2}");
+        assert_eq!(read_backup(&path).await.unwrap(), "{\"version\":1}");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(backup_path(&path))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        restore_backup(&path).await.unwrap();
+        assert_eq!(read(&path).await.unwrap(), "{\"version\":1}");
+    }
+
+    #[tokio::test]
+    async fn corrupt_file_is_not_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session");
+        use_test_key(&path);
+        std::fs::write(&path, r#"{"token":"sec"#).unwrap();
+
+        assert!(read(&path).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), r#"{"token":"sec"#);
+    }
+}
@@ -0,0 +1,135 @@
+use log::{info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{watch, Notify};
+
+/// How long the handlers and verification flows in flight get to finish once
+/// a shutdown is requested.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Coordinates a graceful shutdown: whether it was requested, and the work
+/// that should be finished first.
+#[derive(Debug)]
+pub struct Shutdown {
+    requested: watch::Sender<bool>,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            requested: watch::channel(false).0,
+            in_flight: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Ask everything to stop.
+    pub fn request(&self) {
+        self.requested.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Wait until a shutdown is requested.
+    pub async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        // The sender lives as long as `self`, so this can't fail.
+        let _ = requested.wait_for(|requested| *requested).await;
+    }
+
+    /// Mark some work as in flight until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    /// Whether no work is in flight.
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+    }
+
+    /// Wait for the work in flight to finish, for at most `timeout`.
+    ///
+    /// Returns whether everything finished in time.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.is_idle() {
+                    break;
+                }
+                idle.await;
+            }
+        };
+
+        if tokio::time::timeout(timeout, wait).await.is_ok() {
+            return true;
+        }
+
+        warn!(
+            "Gave up on {} task(s) still running after {}s",
+            self.in_flight.load(Ordering::SeqCst),
+            timeout.as_secs()
+        );
+        false
+    }
+
+    /// Request a shutdown on the first SIGINT or SIGTERM, and exit right away
+    /// on the second one.
+    pub async fn listen_for_signals(self: Arc<Self>) -> anyhow::Result<()> {
+        let signal = wait_for_signal().await?;
+        info!("Received {signal}, shutting down… Send it again to exit right away");
+        self.request();
+
+        let signal = wait_for_signal().await?;
+        warn!("Received {signal} again, exiting without waiting");
+        std::process::exit(EXIT_FORCED);
+    }
+}
+
+/// The exit code when a second signal interrupts the shutdown.
+const EXIT_FORCED: i32 = 130;
+
+/// Marks some work as in flight, see [`Shutdown::track`].
+#[derive(Debug)]
+pub struct InFlight(Arc<Shutdown>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Wait for SIGINT or SIGTERM, and return its name.
+#[cfg(unix)]
+async fn wait_for_signal() -> anyhow::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    Ok(tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    })
+}
+
+/// Wait for Ctrl-C, and return its name.
+#[cfg(not(unix))]
+async fn wait_for_signal() -> anyhow::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
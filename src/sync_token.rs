@@ -0,0 +1,181 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tokio::{fs, sync::Mutex};
+
+use crate::secrets;
+
+/// The name of the file of the sync token in the data directory.
+pub const SYNC_TOKEN_FILE_NAME: &str = "sync_token";
+
+/// How long a new sync token can wait before being written.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many sync responses can be received before the token is written.
+const PERSIST_EVERY: u32 = 20;
+
+/// The sync token of the last sync response, stored next to the session.
+///
+/// Writes are batched, so the token on disk can lag behind by a few responses
+/// until [`SyncTokenStore::flush`] is called.
+#[derive(Debug)]
+pub struct SyncTokenStore {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The token that wasn't written yet.
+    pending: Option<String>,
+
+    /// The number of responses received since the last write.
+    pending_count: u32,
+
+    last_persisted: Instant,
+}
+
+impl SyncTokenStore {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            path: data_dir.join(SYNC_TOKEN_FILE_NAME),
+            state: Mutex::new(State {
+                pending: None,
+                pending_count: 0,
+                last_persisted: Instant::now(),
+            }),
+        }
+    }
+
+    /// The persisted sync token, if any.
+    pub async fn load(&self) -> anyhow::Result<Option<String>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let token = fs::read_to_string(&self.path).await?;
+        let token = token.trim();
+        Ok((!token.is_empty()).then(|| token.to_owned()))
+    }
+
+    /// Record a new sync token, and write it if enough time or responses went
+    /// by since the last write.
+    pub async fn set(&self, token: String) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        state.pending = Some(token);
+        state.pending_count += 1;
+
+        if state.pending_count >= PERSIST_EVERY
+            || state.last_persisted.elapsed() >= PERSIST_INTERVAL
+        {
+            self.write(&mut state).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Record a new sync token and write it right away.
+    pub async fn persist(&self, token: String) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        state.pending = Some(token);
+        self.write(&mut state).await
+    }
+
+    /// Write the pending sync token, if any.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        self.write(&mut state).await
+    }
+
+    /// Delete the persisted sync token, when the session it belongs to is
+    /// gone.
+    pub async fn clear(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().await;
+        state.pending = None;
+        state.pending_count = 0;
+
+        if self.path.exists() {
+            fs::remove_file(&self.path).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, state: &mut State) -> anyhow::Result<()> {
+        let Some(token) = &state.pending else {
+            return Ok(());
+        };
+
+        secrets::write_atomically(&self.path, token.as_bytes()).await?;
+
+        state.pending = None;
+        state.pending_count = 0;
+        state.last_persisted = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn persisted(store: &SyncTokenStore) -> Option<String> {
+        store.load().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_are_batched_by_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SyncTokenStore::new(dir.path());
+
+        for i in 1..PERSIST_EVERY {
+            store.set(format!("s{i}")).await.unwrap();
+        }
+        assert_eq!(persisted(&store).await, None);
+
+        store.set(format!("s{PERSIST_EVERY}")).await.unwrap();
+        assert_eq!(persisted(&store).await, Some(format!("s{PERSIST_EVERY}")));
+
+        // The count starts over.
+        store.set("next".to_owned()).await.unwrap();
+        assert_eq!(persisted(&store).await, Some(format!("s{PERSIST_EVERY}")));
+    }
+
+    #[tokio::test]
+    async fn writes_are_batched_by_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SyncTokenStore::new(dir.path());
+
+        store.set("s1".to_owned()).await.unwrap();
+        assert_eq!(persisted(&store).await, None);
+
+        store.state.lock().await.last_persisted = Instant::now() - PERSIST_INTERVAL;
+        store.set("s2".to_owned()).await.unwrap();
+        assert_eq!(persisted(&store).await.as_deref(), Some("s2"));
+    }
+
+    #[tokio::test]
+    async fn flush_and_persist_write_right_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SyncTokenStore::new(dir.path());
+
+        // Nothing to write.
+        store.flush().await.unwrap();
+        assert!(!dir.path().join(SYNC_TOKEN_FILE_NAME).exists());
+
+        store.set("s1".to_owned()).await.unwrap();
+        store.flush().await.unwrap();
+        assert_eq!(persisted(&store).await.as_deref(), Some("s1"));
+
+        store.persist("s2".to_owned()).await.unwrap();
+        assert_eq!(persisted(&store).await.as_deref(), Some("s2"));
+
+        // A new store, as after a restart, reads it back.
+        let store = SyncTokenStore::new(dir.path());
+        assert_eq!(persisted(&store).await.as_deref(), Some("s2"));
+
+        store.clear().await.unwrap();
+        store.flush().await.unwrap();
+        assert_eq!(persisted(&store).await, None);
+    }
+}
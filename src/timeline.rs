@@ -0,0 +1,928 @@
+use eyeball_im::VectorDiff;
+use futures_util::StreamExt;
+use imbl::Vector;
+use log::{info, warn};
+use matrix_sdk::{
+    ruma::{OwnedEventId, OwnedRoomId, OwnedUserId},
+    Client, Room,
+};
+use matrix_sdk_ui::timeline::{
+    EventTimelineItem, PaginationOptions, RoomExt, TimelineItem, TimelineItemContent,
+};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::config::{RoomSelector, TimelineConfig};
+
+/// How many events subscribers can lag behind before missing some.
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Something that happened in the timeline of a watched room.
+#[derive(Debug, Clone)]
+pub enum TimelineEvent {
+    /// A message, sticker or poll appeared in the timeline.
+    ///
+    /// `backfill` is set for the events loaded when starting to watch the
+    /// room, which might have been handled already.
+    MessageAdded {
+        room_id: OwnedRoomId,
+        event_id: OwnedEventId,
+        sender: OwnedUserId,
+        body: String,
+        backfill: bool,
+    },
+
+    /// A message was edited.
+    MessageEdited {
+        room_id: OwnedRoomId,
+        event_id: OwnedEventId,
+        sender: OwnedUserId,
+        body: String,
+    },
+
+    /// A message was redacted.
+    MessageRedacted {
+        room_id: OwnedRoomId,
+        event_id: OwnedEventId,
+        sender: OwnedUserId,
+    },
+
+    /// The reactions to an event changed.
+    ///
+    /// `reactions` holds the count of each reaction key.
+    Reacted {
+        room_id: OwnedRoomId,
+        event_id: OwnedEventId,
+        reactions: Vec<(String, usize)>,
+    },
+
+    /// An event couldn't be decrypted.
+    UnableToDecrypt {
+        room_id: OwnedRoomId,
+        event_id: OwnedEventId,
+        sender: OwnedUserId,
+    },
+
+    /// A state event, like a membership or profile change, was added.
+    StateChanged {
+        room_id: OwnedRoomId,
+        event_id: OwnedEventId,
+        sender: OwnedUserId,
+        kind: StateChangeKind,
+    },
+}
+
+/// The kind of a [`TimelineEvent::StateChanged`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateChangeKind {
+    Membership,
+    Profile,
+    Other,
+}
+
+/// Follows the timeline of the rooms selected in the config, and turns its
+/// changes into [`TimelineEvent`]s.
+///
+/// The rooms stop being watched when the watcher is dropped.
+pub struct TimelineWatcher {
+    rooms: Vec<RoomSelector>,
+    backfill: u16,
+    events: broadcast::Sender<TimelineEvent>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl TimelineWatcher {
+    pub fn new(config: &TimelineConfig) -> Self {
+        Self {
+            rooms: config.rooms.clone(),
+            backfill: config.backfill,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Get the events of all the watched rooms.
+    pub fn subscribe(&self) -> broadcast::Receiver<TimelineEvent> {
+        self.events.subscribe()
+    }
+
+    /// Start watching the rooms.
+    ///
+    /// `*` only covers the rooms joined at this point. Rooms that can't be
+    /// found are skipped with a warning.
+    pub async fn start(&self, client: &Client) -> anyhow::Result<()> {
+        for room in self.resolve_rooms(client).await {
+            self.watch(room).await?;
+        }
+        Ok(())
+    }
+
+    async fn resolve_rooms(&self, client: &Client) -> Vec<Room> {
+        let mut rooms = Vec::new();
+        let mut seen = HashSet::new();
+
+        for selector in &self.rooms {
+            let selected = match selector {
+                RoomSelector::AllJoined => client.joined_rooms(),
+                RoomSelector::Id(room_id) => match client.get_room(room_id) {
+                    Some(room) => vec![room],
+                    None => {
+                        warn!("Room {room_id} is not known, not watching it");
+                        continue;
+                    }
+                },
+                RoomSelector::Alias(alias) => {
+                    let room = match client.resolve_room_alias(alias).await {
+                        Ok(response) => client.get_room(&response.room_id),
+                        Err(error) => {
+                            warn!("Unable to resolve {alias}, not watching it: {error}");
+                            continue;
+                        }
+                    };
+                    match room {
+                        Some(room) => vec![room],
+                        None => {
+                            warn!("Room {alias} is not joined, not watching it");
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            for room in selected {
+                if seen.insert(room.room_id().to_owned()) {
+                    rooms.push(room);
+                }
+            }
+        }
+
+        rooms
+    }
+
+    async fn watch(&self, room: Room) -> anyhow::Result<()> {
+        let timeline = room.timeline().await;
+        if self.backfill > 0 {
+            timeline
+                .paginate_backwards(PaginationOptions::simple_request(self.backfill))
+                .await?;
+        }
+        let (timeline_items, mut timeline_stream) = timeline.subscribe().await;
+
+        let mut mirror = TimelineMirror::new(room.room_id().to_owned());
+        let events = self.events.clone();
+        mirror.reset(timeline_items, true, &mut |event| {
+            // Nobody listening is fine.
+            let _ = events.send(event);
+        });
+
+        let task = tokio::spawn(async move {
+            // Keep the timeline alive as long as we listen to it.
+            let _timeline = timeline;
+            while let Some(diff) = timeline_stream.next().await {
+                mirror.apply(diff, &mut |event| {
+                    let _ = events.send(event);
+                });
+            }
+        });
+        self.tasks.lock().unwrap().push(task);
+
+        Ok(())
+    }
+}
+
+impl Drop for TimelineWatcher {
+    fn drop(&mut self) {
+        for task in self.tasks.get_mut().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// A copy of the items of a timeline, to compare the items before and after
+/// a change.
+struct TimelineMirror {
+    room_id: OwnedRoomId,
+    items: Vector<Arc<TimelineItem>>,
+}
+
+impl TimelineMirror {
+    fn new(room_id: OwnedRoomId) -> Self {
+        Self {
+            room_id,
+            items: Vector::new(),
+        }
+    }
+
+    /// Apply a diff to the mirror and emit the events it represents.
+    fn apply(&mut self, diff: VectorDiff<Arc<TimelineItem>>, emit: &mut impl FnMut(TimelineEvent)) {
+        match diff {
+            VectorDiff::Append { values } => {
+                for value in values {
+                    self.added(&value, false, emit);
+                    self.items.push_back(value);
+                }
+            }
+            VectorDiff::PushBack { value } => {
+                self.added(&value, false, emit);
+                self.items.push_back(value);
+            }
+            VectorDiff::PushFront { value } => {
+                // Items are pushed at the front when paginating backwards.
+                self.added(&value, true, emit);
+                self.items.push_front(value);
+            }
+            VectorDiff::Insert { index, value } => {
+                self.added(&value, false, emit);
+                self.items.insert(index, value);
+            }
+            VectorDiff::Set { index, value } => {
+                let old = self.items.set(index, value.clone());
+                self.changed(&old, &value, emit);
+            }
+            VectorDiff::Remove { index } => {
+                self.items.remove(index);
+            }
+            VectorDiff::PopFront => {
+                self.items.pop_front();
+            }
+            VectorDiff::PopBack => {
+                self.items.pop_back();
+            }
+            VectorDiff::Truncate { length } => {
+                self.items.truncate(length);
+            }
+            VectorDiff::Clear => {
+                self.items.clear();
+            }
+            VectorDiff::Reset { values } => {
+                self.reset(values, false, emit);
+            }
+        }
+    }
+
+    /// Replace all the items, emitting events only for the ones that weren't
+    /// there before.
+    fn reset(
+        &mut self,
+        items: Vector<Arc<TimelineItem>>,
+        backfill: bool,
+        emit: &mut impl FnMut(TimelineEvent),
+    ) {
+        let known: HashSet<_> = self
+            .items
+            .iter()
+            .filter_map(|item| item.as_event()?.event_id().map(ToOwned::to_owned))
+            .collect();
+
+        for item in &items {
+            let is_known = item
+                .as_event()
+                .and_then(EventTimelineItem::event_id)
+                .is_some_and(|event_id| known.contains(event_id));
+            if !is_known {
+                self.added(item, backfill, emit);
+            }
+        }
+
+        self.items = items;
+    }
+
+    /// Emit the event for a new item.
+    fn added(&self, item: &TimelineItem, backfill: bool, emit: &mut impl FnMut(TimelineEvent)) {
+        // Day dividers and read markers are virtual items, not events.
+        let Some(event) = item.as_event() else {
+            return;
+        };
+        // Local echoes are emitted once the server has the event.
+        let Some(event_id) = event.event_id() else {
+            return;
+        };
+
+        let room_id = self.room_id.clone();
+        let event_id = event_id.to_owned();
+        let sender = event.sender().to_owned();
+
+        let timeline_event = match event.content() {
+            TimelineItemContent::Message(message) => TimelineEvent::MessageAdded {
+                room_id,
+                event_id,
+                sender,
+                body: message.body().to_owned(),
+                backfill,
+            },
+            TimelineItemContent::Sticker(sticker) => TimelineEvent::MessageAdded {
+                room_id,
+                event_id,
+                sender,
+                body: sticker.content().body.clone(),
+                backfill,
+            },
+            TimelineItemContent::Poll(poll) => TimelineEvent::MessageAdded {
+                room_id,
+                event_id,
+                sender,
+                body: poll.fallback_text().unwrap_or_default(),
+                backfill,
+            },
+            TimelineItemContent::RedactedMessage => TimelineEvent::MessageRedacted {
+                room_id,
+                event_id,
+                sender,
+            },
+            TimelineItemContent::UnableToDecrypt(_) => TimelineEvent::UnableToDecrypt {
+                room_id,
+                event_id,
+                sender,
+            },
+            TimelineItemContent::MembershipChange(_) => TimelineEvent::StateChanged {
+                room_id,
+                event_id,
+                sender,
+                kind: StateChangeKind::Membership,
+            },
+            TimelineItemContent::ProfileChange(_) => TimelineEvent::StateChanged {
+                room_id,
+                event_id,
+                sender,
+                kind: StateChangeKind::Profile,
+            },
+            TimelineItemContent::OtherState(_) => TimelineEvent::StateChanged {
+                room_id,
+                event_id,
+                sender,
+                kind: StateChangeKind::Other,
+            },
+            TimelineItemContent::FailedToParseMessageLike { event_type, error } => {
+                warn!("[{room_id}] Unable to parse {event_type} event {event_id}: {error}");
+                return;
+            }
+            TimelineItemContent::FailedToParseState {
+                event_type, error, ..
+            } => {
+                warn!("[{room_id}] Unable to parse {event_type} state event {event_id}: {error}");
+                return;
+            }
+        };
+        emit(timeline_event);
+
+        let reactions = reaction_counts(event);
+        if !reactions.is_empty() {
+            emit(TimelineEvent::Reacted {
+                room_id: self.room_id.clone(),
+                event_id: event_id_of(event),
+                reactions,
+            });
+        }
+    }
+
+    /// Emit the events for an item that was replaced by a new version.
+    fn changed(
+        &self,
+        old: &TimelineItem,
+        new: &TimelineItem,
+        emit: &mut impl FnMut(TimelineEvent),
+    ) {
+        let Some(new_event) = new.as_event() else {
+            return;
+        };
+        let Some(old_event) = old.as_event() else {
+            return self.added(new, false, emit);
+        };
+        // A local echo that got its event ID is new for us.
+        if old_event.event_id().is_none() || old_event.event_id() != new_event.event_id() {
+            return self.added(new, false, emit);
+        }
+
+        let room_id = self.room_id.clone();
+        let event_id = event_id_of(new_event);
+        let sender = new_event.sender().to_owned();
+
+        match (old_event.content(), new_event.content()) {
+            (TimelineItemContent::RedactedMessage, _) => {}
+            (_, TimelineItemContent::RedactedMessage) => {
+                emit(TimelineEvent::MessageRedacted {
+                    room_id: room_id.clone(),
+                    event_id: event_id.clone(),
+                    sender,
+                });
+            }
+            (TimelineItemContent::Message(old), TimelineItemContent::Message(new))
+                if new.is_edited() && (!old.is_edited() || old.body() != new.body()) =>
+            {
+                emit(TimelineEvent::MessageEdited {
+                    room_id: room_id.clone(),
+                    event_id: event_id.clone(),
+                    sender,
+                    body: new.body().to_owned(),
+                });
+            }
+            // A late decryption turns the item into a message.
+            (TimelineItemContent::UnableToDecrypt(_), TimelineItemContent::UnableToDecrypt(_)) => {}
+            (TimelineItemContent::UnableToDecrypt(_), _) => {
+                return self.added(new, false, emit);
+            }
+            _ => {}
+        }
+
+        let reactions = reaction_counts(new_event);
+        if reactions != reaction_counts(old_event) {
+            emit(TimelineEvent::Reacted {
+                room_id,
+                event_id,
+                reactions,
+            });
+        }
+    }
+}
+
+/// The event ID of a remote event.
+fn event_id_of(event: &EventTimelineItem) -> OwnedEventId {
+    event
+        .event_id()
+        .expect("Only remote events are tracked")
+        .to_owned()
+}
+
+fn reaction_counts(event: &EventTimelineItem) -> Vec<(String, usize)> {
+    event
+        .reactions()
+        .iter()
+        .map(|(key, group)| (key.clone(), group.len()))
+        .collect()
+}
+
+/// Log the events of the watched timelines, until the watcher is dropped.
+pub async fn log_events(mut events: broadcast::Receiver<TimelineEvent>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                warn!("Timeline logger lagged behind, {count} events were skipped");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        match event {
+            TimelineEvent::MessageAdded {
+                room_id,
+                event_id,
+                sender,
+                body,
+                backfill,
+            } => {
+                let origin = if backfill { " (backfill)" } else { "" };
+                info!("[{room_id}] Timeline{origin}: {sender} sent {event_id}: {body}");
+            }
+            TimelineEvent::MessageEdited {
+                room_id,
+                event_id,
+                sender,
+                body,
+            } => info!("[{room_id}] Timeline: {sender} edited {event_id}: {body}"),
+            TimelineEvent::MessageRedacted {
+                room_id,
+                event_id,
+                sender,
+            } => info!("[{room_id}] Timeline: {event_id} of {sender} was redacted"),
+            TimelineEvent::Reacted {
+                room_id,
+                event_id,
+                reactions,
+            } => {
+                let reactions: Vec<_> = reactions
+                    .iter()
+                    .map(|(key, count)| format!("{key}×{count}"))
+                    .collect();
+                info!(
+                    "[{room_id}] Timeline: reactions to {event_id}: {}",
+                    reactions.join(" ")
+                );
+            }
+            TimelineEvent::UnableToDecrypt {
+                room_id,
+                event_id,
+                sender,
+            } => info!("[{room_id}] Timeline: unable to decrypt {event_id} of {sender}"),
+            TimelineEvent::StateChanged {
+                room_id,
+                event_id,
+                sender,
+                kind,
+            } => info!("[{room_id}] Timeline: {kind:?} change {event_id} by {sender}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::{
+        config::SyncSettings,
+        matrix_auth::{MatrixSession, MatrixSessionTokens},
+        ruma::{event_id, room_id, user_id, EventId},
+        SessionMeta,
+    };
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    const ROOM_ID: &str = "!room:example.org";
+
+    /// A sync response with the given timeline events in the room.
+    fn sync_response(next_batch: &str, events: Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "next_batch": next_batch,
+            "rooms": { "join": { ROOM_ID: { "timeline": { "events": events } } } },
+        }))
+    }
+
+    fn message(event_id: &str, sender: &str, body: &str) -> Value {
+        json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": sender,
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": body },
+        })
+    }
+
+    /// The items of a timeline before and after some changes: new messages,
+    /// an edit, a reaction, a redaction and a late decryption.
+    struct Snapshots {
+        before: Vector<Arc<TimelineItem>>,
+        after: Vector<Arc<TimelineItem>>,
+    }
+
+    async fn snapshots() -> Snapshots {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "versions": ["v1.5"] })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .and(query_param("since", "s1"))
+            .respond_with(sync_response(
+                "s2",
+                json!([
+                    message("$a", "@alice:example.org", "Don't panic"),
+                    message("$b", "@bob:example.org", "Know thyself."),
+                    message("$c", "@alice:example.org", "Oops"),
+                    {
+                        "type": "m.room.member",
+                        "event_id": "$member",
+                        "sender": "@carol:example.org",
+                        "state_key": "@carol:example.org",
+                        "origin_server_ts": 1,
+                        "content": { "membership": "join" },
+                    },
+                    {
+                        "type": "m.room.encrypted",
+                        "event_id": "$encrypted",
+                        "sender": "@bob:example.org",
+                        "origin_server_ts": 1,
+                        "content": {
+                            "algorithm": "m.megolm.v1.aes-sha2",
+                            "ciphertext": "AwgAEnAC",
+                            "sender_key": "aV9BpqYFqJpKYmgERyGv/6QyKMcgLqxM05V0gvzg9Yk",
+                            "device_id": "BOBDEVICE",
+                            "session_id": "EwYNpSxxOahJ8Mh/5vGyGG+UQj2f4TnL/VdBSYGWPcg",
+                        },
+                    },
+                ]),
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .and(query_param("since", "s2"))
+            .respond_with(sync_response(
+                "s3",
+                json!([
+                    {
+                        "type": "m.room.message",
+                        "event_id": "$edit",
+                        "sender": "@alice:example.org",
+                        "origin_server_ts": 2,
+                        "content": {
+                            "msgtype": "m.text",
+                            "body": "* Don't panic!",
+                            "m.new_content": { "msgtype": "m.text", "body": "Don't panic!" },
+                            "m.relates_to": { "rel_type": "m.replace", "event_id": "$a" },
+                        },
+                    },
+                    {
+                        "type": "m.reaction",
+                        "event_id": "$reaction",
+                        "sender": "@alice:example.org",
+                        "origin_server_ts": 2,
+                        "content": {
+                            "m.relates_to": {
+                                "rel_type": "m.annotation",
+                                "event_id": "$b",
+                                "key": "👍",
+                            },
+                        },
+                    },
+                    {
+                        "type": "m.room.redaction",
+                        "event_id": "$redaction",
+                        "sender": "@alice:example.org",
+                        "origin_server_ts": 2,
+                        "redacts": "$c",
+                        "content": { "redacts": "$c" },
+                    },
+                ]),
+            ))
+            .mount(&server)
+            .await;
+        // The first sync, without a token, only joins the room.
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .respond_with(sync_response("s1", json!([])))
+            .with_priority(10)
+            .mount(&server)
+            .await;
+
+        let client = Client::builder()
+            .homeserver_url(server.uri())
+            .build()
+            .await
+            .unwrap();
+        client
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id: user_id!("@bot:example.org").to_owned(),
+                    device_id: "BOTDEVICE".into(),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: "token".to_owned(),
+                    refresh_token: None,
+                },
+            })
+            .await
+            .unwrap();
+
+        let sync = |token: Option<&str>| {
+            let mut settings = SyncSettings::default();
+            if let Some(token) = token {
+                settings = settings.token(token);
+            }
+            client.sync_once(settings)
+        };
+        sync(None).await.unwrap();
+        let timeline = client
+            .get_room(room_id!("!room:example.org"))
+            .unwrap()
+            .timeline()
+            .await;
+
+        sync(Some("s1")).await.unwrap();
+        let before = wait_for(&timeline, |items| event_count(items) == 5).await;
+        sync(Some("s2")).await.unwrap();
+        let after = wait_for(&timeline, |items| {
+            let item = find(items, event_id!("$c"));
+            matches!(item.content(), TimelineItemContent::RedactedMessage)
+        })
+        .await;
+
+        Snapshots { before, after }
+    }
+
+    /// Wait for the timeline to process a sync response.
+    async fn wait_for(
+        timeline: &matrix_sdk_ui::Timeline,
+        done: impl Fn(&Vector<Arc<TimelineItem>>) -> bool,
+    ) -> Vector<Arc<TimelineItem>> {
+        for _ in 0..50 {
+            let items = timeline.items().await;
+            if done(&items) {
+                return items;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("The timeline didn't get the sync response");
+    }
+
+    fn event_count(items: &Vector<Arc<TimelineItem>>) -> usize {
+        items
+            .iter()
+            .filter(|item| item.as_event().is_some())
+            .count()
+    }
+
+    fn find<'a>(items: &'a Vector<Arc<TimelineItem>>, event_id: &EventId) -> &'a EventTimelineItem {
+        items
+            .iter()
+            .filter_map(|item| item.as_event())
+            .find(|event| event.event_id() == Some(event_id))
+            .unwrap()
+    }
+
+    fn item(items: &Vector<Arc<TimelineItem>>, event_id: &EventId) -> Arc<TimelineItem> {
+        items
+            .iter()
+            .find(|item| item.as_event().and_then(EventTimelineItem::event_id) == Some(event_id))
+            .unwrap()
+            .clone()
+    }
+
+    /// Apply the diff and return the events it emitted.
+    fn apply(
+        mirror: &mut TimelineMirror,
+        diff: VectorDiff<Arc<TimelineItem>>,
+    ) -> Vec<TimelineEvent> {
+        let mut events = Vec::new();
+        mirror.apply(diff, &mut |event| events.push(event));
+        events
+    }
+
+    /// A short description of an event, to compare them.
+    fn describe(event: &TimelineEvent) -> String {
+        match event {
+            TimelineEvent::MessageAdded {
+                event_id,
+                body,
+                backfill,
+                ..
+            } => format!("added {event_id} {body} backfill={backfill}"),
+            TimelineEvent::MessageEdited { event_id, body, .. } => {
+                format!("edited {event_id} {body}")
+            }
+            TimelineEvent::MessageRedacted { event_id, .. } => format!("redacted {event_id}"),
+            TimelineEvent::Reacted {
+                event_id,
+                reactions,
+                ..
+            } => format!("reacted {event_id} {reactions:?}"),
+            TimelineEvent::UnableToDecrypt { event_id, .. } => format!("utd {event_id}"),
+            TimelineEvent::StateChanged { event_id, kind, .. } => {
+                format!("state {event_id} {kind:?}")
+            }
+        }
+    }
+
+    fn describe_all(events: &[TimelineEvent]) -> Vec<String> {
+        events.iter().map(describe).collect()
+    }
+
+    fn mirror() -> TimelineMirror {
+        TimelineMirror::new(room_id!("!room:example.org").to_owned())
+    }
+
+    #[tokio::test]
+    async fn mirror_follows_the_diffs() {
+        let Snapshots { before, after } = snapshots().await;
+        let a = item(&before, event_id!("$a"));
+        let b = item(&before, event_id!("$b"));
+        let c = item(&before, event_id!("$c"));
+        let member = item(&before, event_id!("$member"));
+        let encrypted = item(&before, event_id!("$encrypted"));
+
+        let mut mirror = mirror();
+
+        // Append and Insert emit new live events.
+        let events = apply(
+            &mut mirror,
+            VectorDiff::Append {
+                values: Vector::from(vec![a.clone(), c.clone()]),
+            },
+        );
+        assert_eq!(
+            describe_all(&events),
+            [
+                "added $a Don't panic backfill=false",
+                "added $c Oops backfill=false"
+            ]
+        );
+        let events = apply(
+            &mut mirror,
+            VectorDiff::Insert {
+                index: 1,
+                value: member.clone(),
+            },
+        );
+        assert_eq!(describe_all(&events), ["state $member Membership"]);
+
+        // PushFront comes from back pagination.
+        let events = apply(&mut mirror, VectorDiff::PushFront { value: b.clone() });
+        assert_eq!(
+            describe_all(&events),
+            ["added $b Know thyself. backfill=true"]
+        );
+        let events = apply(
+            &mut mirror,
+            VectorDiff::PushBack {
+                value: encrypted.clone(),
+            },
+        );
+        assert_eq!(describe_all(&events), ["utd $encrypted"]);
+
+        let ids: Vec<_> = mirror
+            .items
+            .iter()
+            .map(|item| item.as_event().unwrap().event_id().unwrap().to_string())
+            .collect();
+        assert_eq!(ids, ["$b", "$a", "$member", "$c", "$encrypted"]);
+
+        // Set compares the new version of an item with the old one.
+        let events = apply(
+            &mut mirror,
+            VectorDiff::Set {
+                index: 1,
+                value: item(&after, event_id!("$a")),
+            },
+        );
+        assert_eq!(describe_all(&events), ["edited $a Don't panic!"]);
+        let events = apply(
+            &mut mirror,
+            VectorDiff::Set {
+                index: 0,
+                value: item(&after, event_id!("$b")),
+            },
+        );
+        assert_eq!(describe_all(&events), [r#"reacted $b [("👍", 1)]"#]);
+        let events = apply(
+            &mut mirror,
+            VectorDiff::Set {
+                index: 3,
+                value: item(&after, event_id!("$c")),
+            },
+        );
+        assert_eq!(describe_all(&events), ["redacted $c"]);
+
+        // The same version again is not a change.
+        let events = apply(
+            &mut mirror,
+            VectorDiff::Set {
+                index: 3,
+                value: item(&after, event_id!("$c")),
+            },
+        );
+        assert!(events.is_empty());
+
+        // A different item at the same place is new.
+        let events = apply(&mut mirror, VectorDiff::Set { index: 4, value: a });
+        assert_eq!(
+            describe_all(&events),
+            ["added $a Don't panic backfill=false"]
+        );
+
+        // Removing items doesn't emit anything.
+        assert!(apply(&mut mirror, VectorDiff::Remove { index: 4 }).is_empty());
+        assert_eq!(mirror.items.len(), 4);
+        assert!(apply(&mut mirror, VectorDiff::Clear).is_empty());
+        assert!(mirror.items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reset_only_emits_the_new_items() {
+        let Snapshots { before, .. } = snapshots().await;
+        let mut mirror = mirror();
+
+        let mut backfilled = Vec::new();
+        mirror.reset(before.clone(), true, &mut |event| backfilled.push(event));
+        assert_eq!(
+            describe_all(&backfilled),
+            [
+                "added $a Don't panic backfill=true",
+                "added $b Know thyself. backfill=true",
+                "added $c Oops backfill=true",
+                "state $member Membership",
+                "utd $encrypted",
+            ]
+        );
+
+        // Only the items that weren't there before are emitted, as live
+        // events.
+        let mut items = Vector::from(vec![item(&before, event_id!("$b"))]);
+        let events = apply(
+            &mut mirror,
+            VectorDiff::Reset {
+                values: items.clone(),
+            },
+        );
+        assert!(events.is_empty());
+
+        items.push_back(item(&before, event_id!("$c")));
+        apply(&mut mirror, VectorDiff::Clear);
+        let events = apply(&mut mirror, VectorDiff::Reset { values: items });
+        assert_eq!(
+            describe_all(&events),
+            [
+                "added $b Know thyself. backfill=false",
+                "added $c Oops backfill=false"
+            ]
+        );
+        assert_eq!(mirror.items.len(), 2);
+    }
+}
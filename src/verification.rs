@@ -0,0 +1,1497 @@
+use anyhow::Context;
+use futures_util::{future, stream, Stream, StreamExt};
+use log::{info, warn};
+use matrix_sdk::{
+    encryption::verification::{
+        format_emojis, QrVerification, QrVerificationState, SasState, SasVerification,
+        Verification, VerificationRequest, VerificationRequestState,
+    },
+    event_handler::Ctx,
+    ruma::{
+        events::{
+            key::verification::{request::ToDeviceKeyVerificationRequestEvent, VerificationMethod},
+            room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        },
+        DeviceId, OwnedDeviceId, OwnedUserId, RoomId, UserId,
+    },
+    Client, RoomState,
+};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    future::Future,
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    config::{Config, ConfirmMethod},
+    devices,
+    error::{BotError, BotResult, ErrorReporter},
+    shutdown::Shutdown,
+};
+
+/// The name of the audit log of the verifications in the data directory.
+pub const AUDIT_LOG_FILE_NAME: &str = "verification.log";
+
+/// Applies the verification policy of the config: who can verify the bot, and
+/// how verifications are confirmed.
+///
+/// It outlives the clients of a session, so confirmations can be answered
+/// while the client is restored.
+#[derive(Debug)]
+pub struct Verifier {
+    config: Arc<Config>,
+
+    /// How verifications are confirmed, from the config unless overridden.
+    confirm: ConfirmMethod,
+
+    audit_log: PathBuf,
+    reporter: Arc<ErrorReporter>,
+    shutdown: Arc<Shutdown>,
+    pending: Mutex<PendingConfirmations>,
+}
+
+/// The verifications waiting for an operator, by ID.
+#[derive(Debug, Default)]
+struct PendingConfirmations {
+    next_id: u64,
+    by_id: BTreeMap<u64, PendingConfirmation>,
+}
+
+#[derive(Debug)]
+struct PendingConfirmation {
+    user_id: OwnedUserId,
+    device_id: OwnedDeviceId,
+    flow_id: String,
+
+    /// What the operator is asked to compare.
+    prompt: String,
+
+    deadline: Instant,
+    answer: oneshot::Sender<Answer>,
+}
+
+/// The answer of an operator.
+#[derive(Debug)]
+struct Answer {
+    confirmed: bool,
+
+    /// Who answered, for the audit log.
+    by: String,
+}
+
+/// How waiting for the answer of a confirmation ended.
+#[derive(Debug)]
+enum Outcome {
+    Answered(Answer),
+    TimedOut,
+    Interrupted,
+
+    /// The flow was cancelled before it was answered.
+    Over,
+}
+
+/// An entry of the audit log, written as a line of JSON.
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    event: &'a str,
+    user_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<&'a str>,
+    flow_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+}
+
+impl Verifier {
+    pub fn new(
+        config: Arc<Config>,
+        data_dir: &Path,
+        reporter: Arc<ErrorReporter>,
+        shutdown: Arc<Shutdown>,
+    ) -> Self {
+        Self {
+            confirm: config.verification.confirm,
+            config,
+            audit_log: data_dir.join(AUDIT_LOG_FILE_NAME),
+            reporter,
+            shutdown,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Confirm on the terminal whatever the config says, when an operator
+    /// runs a command.
+    pub fn confirm_on_terminal(mut self) -> Self {
+        self.confirm = ConfirmMethod::Stdin;
+        self
+    }
+
+    /// Whether `user_id` may verify the bot.
+    fn is_allowed(&self, user_id: &UserId) -> bool {
+        let allowed_users = &self.config.verification.allowed_users;
+        allowed_users.is_empty() || allowed_users.iter().any(|allowed| allowed == user_id)
+    }
+
+    /// Whether confirmations can be answered in this room.
+    pub fn is_admin_room(&self, room_id: &RoomId) -> bool {
+        self.config.verification.admin_room.as_deref() == Some(room_id)
+    }
+
+    /// Describe the verifications waiting for an operator.
+    pub fn list(&self) -> String {
+        let pending = self.pending.lock().unwrap();
+        if pending.by_id.is_empty() {
+            return "No verification is waiting for a confirmation".to_owned();
+        }
+
+        let mut list = String::new();
+        for (id, confirmation) in &pending.by_id {
+            let _ = writeln!(
+                list,
+                "#{id} {} ({}), {}s left, {}",
+                confirmation.user_id,
+                confirmation.device_id,
+                confirmation
+                    .deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs(),
+                confirmation.prompt
+            );
+        }
+        list.trim_end().to_owned()
+    }
+
+    /// Confirm or cancel a pending verification on behalf of `by`.
+    ///
+    /// Returns a message for the operator.
+    pub fn answer(&self, id: u64, confirmed: bool, by: String) -> String {
+        let Some(confirmation) = self.pending.lock().unwrap().by_id.remove(&id) else {
+            return format!("There is no verification #{id} waiting for a confirmation");
+        };
+
+        // The flow may have been cancelled in the meantime, it says so in the
+        // audit log.
+        let _ = confirmation.answer.send(Answer { confirmed, by });
+        format!(
+            "{} verification #{id} with {}",
+            if confirmed {
+                "Confirming"
+            } else {
+                "Cancelling"
+            },
+            confirmation.user_id
+        )
+    }
+
+    /// Forget the pending confirmation of a flow that is over.
+    fn withdraw(&self, flow_id: &str) {
+        self.pending
+            .lock()
+            .unwrap()
+            .by_id
+            .retain(|_, confirmation| confirmation.flow_id != flow_id);
+    }
+
+    /// Record a step of a verification in the audit log.
+    async fn audit(
+        &self,
+        event: &str,
+        user_id: &UserId,
+        device_id: Option<&DeviceId>,
+        flow_id: &str,
+        detail: Option<&str>,
+    ) {
+        info!(
+            "Verification {flow_id} with {user_id}{}: {event}{}",
+            device_id
+                .map(|device_id| format!(" ({device_id})"))
+                .unwrap_or_default(),
+            detail
+                .map(|detail| format!(", {detail}"))
+                .unwrap_or_default()
+        );
+
+        let entry = AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            event,
+            user_id: user_id.as_str(),
+            device_id: device_id.map(DeviceId::as_str),
+            flow_id,
+            detail,
+        };
+
+        if let Err(error) = self.append_to_audit_log(&entry).await {
+            warn!(
+                "Unable to write to the audit log '{}': {error}",
+                self.audit_log.to_string_lossy()
+            );
+        }
+    }
+
+    async fn append_to_audit_log(&self, entry: &AuditEntry<'_>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        // Only the bot's user can read who verified it.
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&self.audit_log).await?;
+        // A single write, so concurrent entries don't interleave.
+        file.write_all(&line).await?;
+        // Tokio files write in the background, wait for it.
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Get a verification confirmed according to the config, then confirm or
+    /// cancel it.
+    ///
+    /// `prompt` tells the operator what to compare with the other device.
+    async fn confirm(self: Arc<Self>, client: Client, flow: Flow, prompt: String) -> BotResult {
+        let user_id = flow.user_id().to_owned();
+        let device_id = flow.device_id().to_owned();
+        let flow_id = flow.flow_id().to_owned();
+        let timeout = Duration::from_secs(self.config.verification.timeout_secs);
+
+        let answer = async {
+            match self.confirm {
+                ConfirmMethod::Auto => Ok(Some(Answer {
+                    confirmed: true,
+                    by: "the allowlist".to_owned(),
+                })),
+                ConfirmMethod::Stdin => ask_on_stdin(&flow, &prompt).await.map(Some),
+                ConfirmMethod::Operator => {
+                    let answer = self.ask_operator(&client, &flow, &prompt, timeout).await;
+                    // The sender is dropped when the flow is cancelled.
+                    Ok(answer.await.ok())
+                }
+            }
+        };
+
+        let answer = match self.wait_for_answer(&flow_id, timeout, answer).await? {
+            Outcome::Answered(answer) => answer,
+            Outcome::TimedOut => {
+                self.audit("timed_out", &user_id, Some(&device_id), &flow_id, None)
+                    .await;
+                flow.cancel().await?;
+                return Ok(());
+            }
+            Outcome::Interrupted => {
+                let detail = Some("shutting down");
+                self.audit("interrupted", &user_id, Some(&device_id), &flow_id, detail)
+                    .await;
+                flow.cancel().await?;
+                return Ok(());
+            }
+            // The flow is over, the state handler logged why.
+            Outcome::Over => return Ok(()),
+        };
+
+        let detail = format!("by {}", answer.by);
+        if answer.confirmed {
+            self.audit(
+                "confirmed",
+                &user_id,
+                Some(&device_id),
+                &flow_id,
+                Some(&detail),
+            )
+            .await;
+            flow.confirm().await?;
+        } else {
+            self.audit(
+                "denied",
+                &user_id,
+                Some(&device_id),
+                &flow_id,
+                Some(&detail),
+            )
+            .await;
+            flow.cancel().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the answer of a confirmation, until `timeout` or the
+    /// shutdown.
+    ///
+    /// The pending confirmation of the flow is withdrawn if there is no
+    /// answer.
+    async fn wait_for_answer(
+        &self,
+        flow_id: &str,
+        timeout: Duration,
+        answer: impl Future<Output = BotResult<Option<Answer>>>,
+    ) -> BotResult<Outcome> {
+        tokio::select! {
+            answer = tokio::time::timeout(timeout, answer) => match answer {
+                Ok(answer) => Ok(answer?.map_or(Outcome::Over, Outcome::Answered)),
+                Err(_) => {
+                    self.withdraw(flow_id);
+                    Ok(Outcome::TimedOut)
+                }
+            },
+            () = self.shutdown.requested() => {
+                self.withdraw(flow_id);
+                Ok(Outcome::Interrupted)
+            }
+        }
+    }
+
+    /// Post a verification for an operator to answer.
+    async fn ask_operator(
+        &self,
+        client: &Client,
+        flow: &Flow,
+        prompt: &str,
+        timeout: Duration,
+    ) -> oneshot::Receiver<Answer> {
+        let user_id = flow.user_id().to_owned();
+        let device_id = flow.device_id().to_owned();
+        let (id, receiver) = self.add_pending(
+            user_id.clone(),
+            device_id.clone(),
+            flow.flow_id().to_owned(),
+            prompt.to_owned(),
+            timeout,
+        );
+
+        let detail = format!("#{id}");
+        self.audit(
+            "awaiting_confirmation",
+            &user_id,
+            Some(&device_id),
+            flow.flow_id(),
+            Some(&detail),
+        )
+        .await;
+
+        if self.config.verification.admin_room.is_some() {
+            let prefix = &self.config.command_prefix;
+            let message = RoomMessageEventContent::text_plain(format!(
+                "Verification #{id} with {user_id} ({device_id}), {prompt}\n\
+                 Confirm with `{prefix} verification confirm {id}` or cancel with \
+                 `{prefix} verification cancel {id}` within {}s",
+                timeout.as_secs()
+            ));
+
+            self.post_to_admin_room(client, message, &format!("verification #{id}"))
+                .await;
+        }
+
+        receiver
+    }
+
+    /// Add a confirmation for an operator to answer, and return its ID.
+    fn add_pending(
+        &self,
+        user_id: OwnedUserId,
+        device_id: OwnedDeviceId,
+        flow_id: String,
+        prompt: String,
+        timeout: Duration,
+    ) -> (u64, oneshot::Receiver<Answer>) {
+        let (sender, receiver) = oneshot::channel();
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.next_id += 1;
+        let id = pending.next_id;
+        pending.by_id.insert(
+            id,
+            PendingConfirmation {
+                user_id,
+                device_id,
+                flow_id,
+                prompt,
+                deadline: Instant::now() + timeout,
+                answer: sender,
+            },
+        );
+
+        (id, receiver)
+    }
+
+    /// Post a message to the admin room, if there is one.
+    ///
+    /// `what` names the message in the warnings when it can't be posted.
+    async fn post_to_admin_room(
+        &self,
+        client: &Client,
+        message: RoomMessageEventContent,
+        what: &str,
+    ) {
+        let Some(admin_room) = &self.config.verification.admin_room else {
+            return;
+        };
+
+        match client.get_room(admin_room) {
+            Some(room) if room.state() == RoomState::Joined => {
+                if let Err(error) = room.send(message).await {
+                    warn!("Unable to post {what} to the admin room: {error}");
+                }
+            }
+            _ => warn!("The admin room {admin_room} is not joined, unable to post {what}"),
+        }
+    }
+}
+
+/// A verification that needs a human to compare something with the other
+/// device.
+///
+/// The flows don't know the ID of their request, so it is kept next to them.
+#[derive(Debug, Clone)]
+enum Flow {
+    Sas {
+        sas: SasVerification,
+        flow_id: String,
+    },
+    Qr {
+        qr: QrVerification,
+        flow_id: String,
+    },
+}
+
+impl Flow {
+    fn user_id(&self) -> &UserId {
+        match self {
+            Self::Sas { sas, .. } => sas.other_device().user_id(),
+            Self::Qr { qr, .. } => qr.other_user_id(),
+        }
+    }
+
+    fn device_id(&self) -> &DeviceId {
+        match self {
+            Self::Sas { sas, .. } => sas.other_device().device_id(),
+            Self::Qr { qr, .. } => qr.other_device().device_id(),
+        }
+    }
+
+    fn flow_id(&self) -> &str {
+        match self {
+            Self::Sas { flow_id, .. } | Self::Qr { flow_id, .. } => flow_id,
+        }
+    }
+
+    async fn confirm(&self) -> BotResult {
+        match self {
+            Self::Sas { sas, .. } => sas.confirm().await?,
+            Self::Qr { qr, .. } => qr.confirm().await?,
+        }
+        Ok(())
+    }
+
+    async fn cancel(&self) -> BotResult {
+        match self {
+            Self::Sas { sas, .. } => sas.cancel().await?,
+            Self::Qr { qr, .. } => qr.cancel().await?,
+        }
+        Ok(())
+    }
+}
+
+/// Ask on the terminal whether the verification should be confirmed.
+async fn ask_on_stdin(flow: &Flow, prompt: &str) -> BotResult<Answer> {
+    // One prompt at a time, the others wait for their turn.
+    let mut lines = stdin_lines().lock().await;
+    // Lines typed while nothing was asked answer nothing.
+    while lines.try_recv().is_ok() {}
+
+    println!(
+        "\nVerification with {} ({}), {prompt}",
+        flow.user_id(),
+        flow.device_id()
+    );
+    print!("Confirm with `yes` or cancel with `no`: ");
+    std::io::stdout()
+        .flush()
+        .map_err(|error| BotError::Other(error.into()))?;
+
+    let Some(input) = lines.recv().await else {
+        return Err(BotError::Other(anyhow::anyhow!(
+            "stdin is closed, unable to confirm the verification"
+        )));
+    };
+
+    Ok(Answer {
+        confirmed: matches!(input.trim().to_lowercase().as_ref(), "yes" | "true" | "ok"),
+        by: "the terminal".to_owned(),
+    })
+}
+
+/// The lines read from stdin.
+///
+/// Reading stdin blocks and can't be cancelled, so it happens on a thread of
+/// its own that is never waited for: an interrupted prompt doesn't keep the
+/// process from exiting.
+fn stdin_lines() -> &'static tokio::sync::Mutex<mpsc::UnboundedReceiver<String>> {
+    static LINES: OnceLock<tokio::sync::Mutex<mpsc::UnboundedReceiver<String>>> = OnceLock::new();
+
+    LINES.get_or_init(|| {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let stdin = std::io::stdin();
+            loop {
+                let mut line = String::new();
+                match stdin.read_line(&mut line) {
+                    // Closed.
+                    Ok(0) => break,
+                    Ok(_) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        warn!("Unable to read stdin: {error}");
+                        break;
+                    }
+                }
+            }
+        });
+        tokio::sync::Mutex::new(receiver)
+    })
+}
+
+/// Render a QR code with Unicode blocks, two rows per line.
+///
+/// On a dark background, like most terminals, the light modules are the drawn
+/// ones.
+fn render_qr_code(code: &QrCode, dark_background: bool) -> String {
+    let mut renderer = code.render::<Dense1x2>();
+    if dark_background {
+        renderer
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark);
+    }
+    renderer.build()
+}
+
+async fn print_devices(user_id: &UserId, client: &Client) -> BotResult {
+    println!("Devices of user {user_id}");
+    println!(
+        "{}",
+        devices::format_table(&devices::list(client, user_id).await?)
+    );
+    Ok(())
+}
+
+async fn sas_verification_handler(
+    client: Client,
+    verifier: Arc<Verifier>,
+    flow_id: String,
+    sas: SasVerification,
+) -> BotResult {
+    let user_id = sas.other_device().user_id().to_owned();
+    let device_id = sas.other_device().device_id().to_owned();
+
+    println!("Starting verification with {user_id} {device_id}");
+    print_devices(&user_id, &client).await?;
+    if !sas.we_started() {
+        sas.accept().await?;
+    }
+
+    let mut stream = sas.changes();
+
+    while let Some(state) = stream.next().await {
+        match state {
+            SasState::KeysExchanged { emojis, decimals } => {
+                // Emojis are easier to compare, but not every client supports
+                // them.
+                let prompt = match emojis {
+                    Some(emojis) => {
+                        format!("do these emojis match?\n{}", format_emojis(emojis.emojis))
+                    }
+                    None => format!(
+                        "do these numbers match?\n{} {} {}",
+                        decimals.0, decimals.1, decimals.2
+                    ),
+                };
+
+                verifier.reporter.spawn(
+                    format!("SAS confirmation with {user_id}"),
+                    verifier.clone().confirm(
+                        client.clone(),
+                        Flow::Sas {
+                            sas: sas.clone(),
+                            flow_id: flow_id.clone(),
+                        },
+                        prompt,
+                    ),
+                );
+            }
+            SasState::Done { .. } => {
+                verifier.withdraw(&flow_id);
+                verifier
+                    .audit("verified", &user_id, Some(&device_id), &flow_id, None)
+                    .await;
+
+                print_devices(&user_id, &client).await?;
+
+                break;
+            }
+            SasState::Cancelled(cancel_info) => {
+                verifier.withdraw(&flow_id);
+                verifier
+                    .audit(
+                        "cancelled",
+                        &user_id,
+                        Some(&device_id),
+                        &flow_id,
+                        Some(cancel_info.reason()),
+                    )
+                    .await;
+
+                break;
+            }
+            SasState::Started { .. } | SasState::Accepted { .. } | SasState::Confirmed => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Show a QR code for the other device to scan, and confirm the verification
+/// once it did.
+///
+/// Until the code is scanned, the other device can still choose to compare
+/// emojis instead: the SAS verification it started is returned then.
+async fn qr_verification_handler(
+    client: Client,
+    verifier: Arc<Verifier>,
+    request: VerificationRequest,
+    qr: QrVerification,
+) -> BotResult<Option<SasVerification>> {
+    let user_id = qr.other_user_id().to_owned();
+    let device_id = qr.other_device().device_id().to_owned();
+    let flow_id = request.flow_id().to_owned();
+
+    let code = match qr.to_qr_code() {
+        Ok(code) => code,
+        Err(error) => {
+            verifier
+                .audit(
+                    "cancelled",
+                    &user_id,
+                    Some(&device_id),
+                    &flow_id,
+                    Some("unable to encode the QR code"),
+                )
+                .await;
+            qr.cancel().await?;
+            return Err(BotError::Other(error.into()));
+        }
+    };
+    println!(
+        "Scan this QR code with {user_id} {device_id}:\n{}",
+        render_qr_code(&code, true)
+    );
+    if verifier.confirm == ConfirmMethod::Operator {
+        // The operator may not see the terminal.
+        let rendered = render_qr_code(&code, false);
+        let message = RoomMessageEventContent::text_html(
+            format!("Scan this QR code with {user_id} ({device_id}):\n{rendered}"),
+            format!(
+                "<p>Scan this QR code with {} ({}):</p>\n<pre><code>{rendered}</code></pre>",
+                devices::escape_html(user_id.as_str()),
+                devices::escape_html(device_id.as_str()),
+            ),
+        );
+        verifier
+            .post_to_admin_room(&client, message, "the QR code")
+            .await;
+    }
+
+    let mut stream = qr.changes();
+    let mut request_changes = request.changes();
+
+    loop {
+        let state = tokio::select! {
+            state = stream.next() => state,
+            Some(state) = request_changes.next() => {
+                if let VerificationRequestState::Transitioned {
+                    verification: Verification::SasV1(sas),
+                } = state
+                {
+                    // Too late once the code was scanned, keep to the QR code.
+                    if !qr.has_been_scanned() {
+                        println!("{user_id} {device_id} chose to compare emojis instead");
+                        return Ok(Some(sas));
+                    }
+                }
+                continue;
+            }
+            // Nothing may happen until the code is scanned.
+            () = verifier.shutdown.requested() => {
+                qr.cancel().await?;
+                break;
+            }
+        };
+        let Some(state) = state else {
+            break;
+        };
+
+        match state {
+            QrVerificationState::Scanned => {
+                verifier.reporter.spawn(
+                    format!("QR confirmation with {user_id}"),
+                    verifier.clone().confirm(
+                        client.clone(),
+                        Flow::Qr {
+                            qr: qr.clone(),
+                            flow_id: flow_id.clone(),
+                        },
+                        "does the other device say it scanned the QR code successfully?".to_owned(),
+                    ),
+                );
+            }
+            QrVerificationState::Done { .. } => {
+                verifier.withdraw(&flow_id);
+                verifier
+                    .audit("verified", &user_id, Some(&device_id), &flow_id, None)
+                    .await;
+
+                print_devices(&user_id, &client).await?;
+
+                break;
+            }
+            QrVerificationState::Cancelled(cancel_info) => {
+                verifier.withdraw(&flow_id);
+                verifier
+                    .audit(
+                        "cancelled",
+                        &user_id,
+                        Some(&device_id),
+                        &flow_id,
+                        Some(cancel_info.reason()),
+                    )
+                    .await;
+
+                break;
+            }
+            QrVerificationState::Started
+            | QrVerificationState::Confirmed
+            | QrVerificationState::Reciprocated => (),
+        }
+    }
+
+    Ok(None)
+}
+
+/// The methods the bot can verify with: SAS (emojis or numbers), and showing a
+/// QR code for the other device to scan. It can't scan one itself.
+fn supported_methods() -> Vec<VerificationMethod> {
+    vec![
+        VerificationMethod::SasV1,
+        VerificationMethod::QrCodeShowV1,
+        VerificationMethod::ReciprocateV1,
+    ]
+}
+
+/// The states of a request, from the current one.
+///
+/// The changes alone would miss the states the request went through before
+/// they are followed, like `Ready` when it is accepted.
+fn states(request: &VerificationRequest) -> impl Stream<Item = VerificationRequestState> {
+    let changes = request.changes();
+    stream::once(future::ready(request.state())).chain(changes)
+}
+
+async fn request_verification_handler(
+    client: Client,
+    verifier: Arc<Verifier>,
+    request: VerificationRequest,
+) -> BotResult {
+    let user_id = request.other_user_id().to_owned();
+    let flow_id = request.flow_id().to_owned();
+
+    verifier
+        .audit("requested", &user_id, None, &flow_id, None)
+        .await;
+
+    if !verifier.is_allowed(&user_id) {
+        verifier
+            .audit(
+                "rejected",
+                &user_id,
+                None,
+                &flow_id,
+                Some("not in `verification.allowed_users`"),
+            )
+            .await;
+        request.cancel().await?;
+        return Ok(());
+    }
+
+    println!("Accepting verification request from {user_id}");
+    let mut stream = states(&request);
+    request.accept_with_methods(supported_methods()).await?;
+
+    while let Some(state) = stream.next().await {
+        match state {
+            VerificationRequestState::Created { .. }
+            | VerificationRequestState::Requested { .. } => (),
+            VerificationRequestState::Ready { their_methods, .. } => {
+                // The other side starts the flow it wants, but it can only
+                // scan a code we show. Generating it moves the request to the
+                // QR flow, which the other side can still leave for emojis.
+                let qr = if their_methods.contains(&VerificationMethod::QrCodeScanV1) {
+                    request.generate_qr_code().await?
+                } else {
+                    None
+                };
+
+                if qr.is_none() && !their_methods.contains(&VerificationMethod::SasV1) {
+                    let offered = their_methods
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let detail = format!("no method in common, they offered {offered}");
+                    verifier
+                        .audit("rejected", &user_id, None, &flow_id, Some(&detail))
+                        .await;
+                    request.cancel().await?;
+                    break;
+                }
+            }
+            VerificationRequestState::Transitioned { verification } => match verification {
+                Verification::SasV1(sas) => {
+                    verifier.reporter.spawn(
+                        format!("SAS verification with {user_id}"),
+                        sas_verification_handler(client, verifier.clone(), flow_id, sas),
+                    );
+                    break;
+                }
+                Verification::QrV1(qr) => {
+                    let reporter = verifier.reporter.clone();
+                    reporter.spawn(format!("QR verification with {user_id}"), async move {
+                        let sas =
+                            qr_verification_handler(client.clone(), verifier.clone(), request, qr)
+                                .await?;
+                        match sas {
+                            Some(sas) => {
+                                sas_verification_handler(client, verifier, flow_id, sas).await
+                            }
+                            None => Ok(()),
+                        }
+                    });
+                    break;
+                }
+                _ => {
+                    verifier
+                        .audit(
+                            "rejected",
+                            &user_id,
+                            None,
+                            &flow_id,
+                            Some("unsupported verification method"),
+                        )
+                        .await;
+                    request.cancel().await?;
+                    break;
+                }
+            },
+            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Ask `user_id` to verify the bot, on `device_id` or on every device of the
+/// user, and follow the verification until it is over.
+///
+/// Returns whether the verification succeeded.
+pub async fn request(
+    client: &Client,
+    verifier: &Arc<Verifier>,
+    user_id: &UserId,
+    device_id: Option<&DeviceId>,
+) -> anyhow::Result<bool> {
+    let encryption = client.encryption();
+    let request = match device_id {
+        Some(device_id) => {
+            encryption
+                .get_device(user_id, device_id)
+                .await?
+                .with_context(|| format!("Unknown device {device_id} of {user_id}"))?
+                .request_verification_with_methods(supported_methods())
+                .await?
+        }
+        None => {
+            encryption
+                .get_user_identity(user_id)
+                .await?
+                .with_context(|| {
+                    format!(
+                    "{user_id} has no cross-signing identity, give the ID of one of their devices"
+                )
+                })?
+                .request_verification_with_methods(supported_methods())
+                .await?
+        }
+    };
+
+    let flow_id = request.flow_id().to_owned();
+    verifier
+        .audit("request_sent", user_id, device_id, &flow_id, None)
+        .await;
+    println!("Sent a verification request to {user_id}, accept it on the other device…");
+
+    Ok(follow_request(client.clone(), verifier.clone(), request).await?)
+}
+
+/// Start the verification of a request the bot sent, once the other side is
+/// ready.
+async fn follow_request(
+    client: Client,
+    verifier: Arc<Verifier>,
+    request: VerificationRequest,
+) -> BotResult<bool> {
+    let user_id = request.other_user_id().to_owned();
+    let flow_id = request.flow_id().to_owned();
+    let timeout = Duration::from_secs(verifier.config.verification.timeout_secs);
+
+    let mut stream = states(&request);
+
+    loop {
+        let state = tokio::select! {
+            state = tokio::time::timeout(timeout, stream.next()) => match state {
+                Ok(Some(state)) => state,
+                Ok(None) => return Ok(false),
+                Err(_) => {
+                    verifier.audit("timed_out", &user_id, None, &flow_id, None).await;
+                    request.cancel().await?;
+                    return Ok(false);
+                }
+            },
+            () = verifier.shutdown.requested() => {
+                request.cancel().await?;
+                return Ok(false);
+            }
+        };
+
+        match state {
+            VerificationRequestState::Created { .. }
+            | VerificationRequestState::Requested { .. } => (),
+            VerificationRequestState::Ready { their_methods, .. } => {
+                // Emojis or numbers first, they work without cross-signing.
+                if their_methods.contains(&VerificationMethod::SasV1) {
+                    if let Some(sas) = request.start_sas().await? {
+                        return follow_sas(client, verifier, flow_id, sas).await;
+                    }
+                }
+                if their_methods.contains(&VerificationMethod::QrCodeScanV1) {
+                    if let Some(qr) = request.generate_qr_code().await? {
+                        return follow_qr(client, verifier, request, qr).await;
+                    }
+                }
+
+                verifier
+                    .audit(
+                        "cancelled",
+                        &user_id,
+                        None,
+                        &flow_id,
+                        Some("no method in common"),
+                    )
+                    .await;
+                request.cancel().await?;
+                return Ok(false);
+            }
+            // The other side started a flow before us.
+            VerificationRequestState::Transitioned { verification } => match verification {
+                Verification::SasV1(sas) => {
+                    return follow_sas(client, verifier, flow_id, sas).await
+                }
+                Verification::QrV1(qr) => return follow_qr(client, verifier, request, qr).await,
+                _ => {
+                    request.cancel().await?;
+                    return Ok(false);
+                }
+            },
+            VerificationRequestState::Done => return Ok(true),
+            VerificationRequestState::Cancelled(cancel_info) => {
+                verifier
+                    .audit(
+                        "cancelled",
+                        &user_id,
+                        None,
+                        &flow_id,
+                        Some(cancel_info.reason()),
+                    )
+                    .await;
+                return Ok(false);
+            }
+        }
+    }
+}
+
+async fn follow_sas(
+    client: Client,
+    verifier: Arc<Verifier>,
+    flow_id: String,
+    sas: SasVerification,
+) -> BotResult<bool> {
+    sas_verification_handler(client, verifier, flow_id, sas.clone()).await?;
+    Ok(sas.is_done())
+}
+
+async fn follow_qr(
+    client: Client,
+    verifier: Arc<Verifier>,
+    request: VerificationRequest,
+    qr: QrVerification,
+) -> BotResult<bool> {
+    let flow_id = request.flow_id().to_owned();
+    let sas =
+        qr_verification_handler(client.clone(), verifier.clone(), request, qr.clone()).await?;
+    match sas {
+        Some(sas) => follow_sas(client, verifier, flow_id, sas).await,
+        None => Ok(qr.is_done()),
+    }
+}
+
+/// Find the verification request the SDK created for an event, and handle it.
+async fn spawn_request_handler(
+    client: Client,
+    verifier: Arc<Verifier>,
+    user_id: &UserId,
+    flow_id: &str,
+) {
+    let Some(request) = client
+        .encryption()
+        .get_verification_request(user_id, flow_id)
+        .await
+    else {
+        verifier.reporter.report(
+            "Verification request handler",
+            BotError::MissingVerificationRequest {
+                user_id: user_id.to_owned(),
+                flow_id: flow_id.to_owned(),
+            },
+        );
+        return;
+    };
+
+    verifier.reporter.spawn(
+        format!("Verification request from {user_id}"),
+        request_verification_handler(client, verifier.clone(), request),
+    );
+}
+
+/// Answer the verification requests sent to the bot, see [`Verifier`].
+pub fn setup(client: &Client) {
+    println!("Setting up verification…");
+    client.add_event_handler(
+        |ev: ToDeviceKeyVerificationRequestEvent,
+         client: Client,
+         verifier: Ctx<Arc<Verifier>>| async move {
+            spawn_request_handler(
+                client,
+                verifier.0,
+                &ev.sender,
+                ev.content.transaction_id.as_str(),
+            )
+            .await;
+        },
+    );
+
+    client.add_event_handler(
+        |ev: OriginalSyncRoomMessageEvent,
+         client: Client,
+         verifier: Ctx<Arc<Verifier>>| async move {
+            if let MessageType::VerificationRequest(_) = &ev.content.msgtype {
+                spawn_request_handler(client, verifier.0, &ev.sender, ev.event_id.as_str())
+                    .await;
+            }
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::{
+        config::SyncSettings,
+        matrix_auth::{MatrixSession, MatrixSessionTokens},
+        ruma::{user_id, MilliSecondsSinceUnixEpoch},
+        SessionMeta,
+    };
+    use serde_json::{json, Value};
+    use wiremock::{
+        matchers::{method, path, path_regex},
+        Mock, MockServer, Request, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::config::VerificationConfig;
+
+    const ALICE: &str = "@alice:example.org";
+
+    fn verifier(data_dir: &Path, verification: VerificationConfig) -> Arc<Verifier> {
+        let mut config = Config::default();
+        config.verification = verification;
+        let shutdown = Arc::new(Shutdown::default());
+        let reporter = Arc::new(ErrorReporter::new(false, shutdown.clone()));
+        Arc::new(Verifier::new(
+            Arc::new(config),
+            data_dir,
+            reporter,
+            shutdown,
+        ))
+    }
+
+    /// A homeserver that accepts the to-device messages of the bot.
+    async fn mock_homeserver() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "versions": ["v1.5"] })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex("^/_matrix/client/v3/sendToDevice/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    async fn logged_in_client(server: &MockServer) -> Client {
+        let client = Client::builder()
+            .homeserver_url(server.uri())
+            .build()
+            .await
+            .unwrap();
+        client
+            .restore_session(MatrixSession {
+                meta: SessionMeta {
+                    user_id: user_id!("@bot:example.org").to_owned(),
+                    device_id: "BOTDEVICE".into(),
+                },
+                tokens: MatrixSessionTokens {
+                    access_token: "token".to_owned(),
+                    refresh_token: None,
+                },
+            })
+            .await
+            .unwrap();
+        client
+    }
+
+    /// Receive a verification request from Alice, offering `methods`.
+    async fn receive_request(
+        server: &MockServer,
+        client: &Client,
+        methods: &[&str],
+    ) -> VerificationRequest {
+        let response = Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "next_batch": "s1",
+                "to_device": {
+                    "events": [{
+                        "type": "m.key.verification.request",
+                        "sender": ALICE,
+                        "content": {
+                            "from_device": "ALICEDEVICE",
+                            "methods": methods,
+                            "timestamp": MilliSecondsSinceUnixEpoch::now(),
+                            "transaction_id": "flow",
+                        },
+                    }],
+                },
+            })))
+            .mount_as_scoped(server)
+            .await;
+        client.sync_once(SyncSettings::default()).await.unwrap();
+        drop(response);
+
+        client
+            .encryption()
+            .get_verification_request(user_id!("@alice:example.org"), "flow")
+            .await
+            .unwrap()
+    }
+
+    /// The types of the to-device messages sent by the bot.
+    async fn sent_to_device(server: &MockServer) -> Vec<String> {
+        let requests: Vec<Request> = server.received_requests().await.unwrap();
+        requests
+            .iter()
+            .filter_map(|request| {
+                let path = request.url.path();
+                let event_type = path.strip_prefix("/_matrix/client/v3/sendToDevice/")?;
+                Some(event_type.split('/').next().unwrap().to_owned())
+            })
+            .collect()
+    }
+
+    /// The entries of the audit log.
+    fn audit_log(data_dir: &Path) -> Vec<Value> {
+        std::fs::read_to_string(data_dir.join(AUDIT_LOG_FILE_NAME))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn requests_outside_the_allowlist_are_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(
+            dir.path(),
+            VerificationConfig {
+                allowed_users: vec![user_id!("@bob:example.org").to_owned()],
+                ..Default::default()
+            },
+        );
+        assert!(verifier.is_allowed(user_id!("@bob:example.org")));
+        assert!(!verifier.is_allowed(user_id!("@alice:example.org")));
+
+        let server = mock_homeserver().await;
+        let client = logged_in_client(&server).await;
+        let request = receive_request(&server, &client, &["m.sas.v1"]).await;
+
+        request_verification_handler(client, verifier, request.clone())
+            .await
+            .unwrap();
+
+        assert!(request.is_cancelled());
+        assert_eq!(sent_to_device(&server).await, ["m.key.verification.cancel"]);
+        let entries = audit_log(dir.path());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1]["event"], "rejected");
+        assert_eq!(entries[1]["user_id"], ALICE);
+        assert_eq!(entries[1]["detail"], "not in `verification.allowed_users`");
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_common_method_are_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(dir.path(), VerificationConfig::default());
+        let server = mock_homeserver().await;
+        let client = logged_in_client(&server).await;
+        // Alice can only show a QR code, which the bot can't scan.
+        let request =
+            receive_request(&server, &client, &["m.qr_code.show.v1", "m.reciprocate.v1"]).await;
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            request_verification_handler(client, verifier, request.clone()),
+        )
+        .await
+        .expect("The request should be cancelled right away")
+        .unwrap();
+
+        assert!(request.is_cancelled());
+        assert_eq!(
+            sent_to_device(&server).await,
+            ["m.key.verification.ready", "m.key.verification.cancel"]
+        );
+        let entries = audit_log(dir.path());
+        assert_eq!(entries.last().unwrap()["event"], "rejected");
+        assert_eq!(
+            entries.last().unwrap()["detail"],
+            "no method in common, they offered m.qr_code.show.v1, m.reciprocate.v1"
+        );
+    }
+
+    #[tokio::test]
+    async fn own_requests_without_a_common_method_are_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(dir.path(), VerificationConfig::default());
+        let server = mock_homeserver().await;
+        let client = logged_in_client(&server).await;
+        let request =
+            receive_request(&server, &client, &["m.qr_code.show.v1", "m.reciprocate.v1"]).await;
+        // Ready before the bot follows it, as when the other side answers
+        // quickly.
+        request
+            .accept_with_methods(supported_methods())
+            .await
+            .unwrap();
+
+        let verified = tokio::time::timeout(
+            Duration::from_secs(5),
+            follow_request(client, verifier, request.clone()),
+        )
+        .await
+        .expect("The request should be cancelled right away")
+        .unwrap();
+
+        assert!(!verified);
+        assert!(request.is_cancelled());
+        assert_eq!(
+            sent_to_device(&server).await,
+            ["m.key.verification.ready", "m.key.verification.cancel"]
+        );
+        let entries = audit_log(dir.path());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["event"], "cancelled");
+        assert_eq!(entries[0]["detail"], "no method in common");
+    }
+
+    #[tokio::test]
+    async fn audit_log_is_private_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(dir.path(), VerificationConfig::default());
+        let alice = user_id!("@alice:example.org");
+
+        verifier.audit("requested", alice, None, "flow", None).await;
+        verifier
+            .audit(
+                "confirmed",
+                alice,
+                Some("ALICEDEVICE".into()),
+                "flow",
+                Some("by the allowlist"),
+            )
+            .await;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(dir.path().join(AUDIT_LOG_FILE_NAME)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+
+        let entries = audit_log(dir.path());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["event"], "requested");
+        assert_eq!(entries[0]["user_id"], ALICE);
+        assert_eq!(entries[0]["flow_id"], "flow");
+        assert!(entries[0].get("device_id").is_none());
+        assert!(entries[0].get("detail").is_none());
+        assert!(entries[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(entries[1]["event"], "confirmed");
+        assert_eq!(entries[1]["device_id"], "ALICEDEVICE");
+        assert_eq!(entries[1]["detail"], "by the allowlist");
+    }
+
+    #[test]
+    fn everyone_is_allowed_without_an_allowlist() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(dir.path(), VerificationConfig::default());
+        assert!(verifier.is_allowed(user_id!("@alice:example.org")));
+    }
+
+    fn add_pending(verifier: &Verifier, flow_id: &str) -> (u64, oneshot::Receiver<Answer>) {
+        verifier.add_pending(
+            user_id!("@alice:example.org").to_owned(),
+            "ALICEDEVICE".into(),
+            flow_id.to_owned(),
+            "🐶 🔑 🎩".to_owned(),
+            Duration::from_secs(60),
+        )
+    }
+
+    #[tokio::test]
+    async fn confirm_and_cancel_by_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(dir.path(), VerificationConfig::default());
+
+        let (first, first_answer) = add_pending(&verifier, "flow1");
+        let (second, second_answer) = add_pending(&verifier, "flow2");
+        assert_eq!((first, second), (1, 2));
+
+        let list = verifier.list();
+        assert!(list.starts_with("#1 @alice:example.org (ALICEDEVICE), "));
+        assert!(list.contains("\n#2 "));
+        assert!(list.ends_with("🐶 🔑 🎩"));
+
+        assert_eq!(
+            verifier.answer(2, false, "@bob:example.org".to_owned()),
+            "Cancelling verification #2 with @alice:example.org"
+        );
+        let answer = second_answer.await.unwrap();
+        assert!(!answer.confirmed);
+        assert_eq!(answer.by, "@bob:example.org");
+
+        assert_eq!(
+            verifier.answer(1, true, "the control socket".to_owned()),
+            "Confirming verification #1 with @alice:example.org"
+        );
+        let answer = first_answer.await.unwrap();
+        assert!(answer.confirmed);
+        assert_eq!(answer.by, "the control socket");
+
+        // Each verification is answered once.
+        assert_eq!(
+            verifier.answer(1, false, "the control socket".to_owned()),
+            "There is no verification #1 waiting for a confirmation"
+        );
+        assert_eq!(
+            verifier.list(),
+            "No verification is waiting for a confirmation"
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_confirmations_are_withdrawn() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(dir.path(), VerificationConfig::default());
+        let timeout = Duration::from_millis(50);
+
+        let (_, answer) = add_pending(&verifier, "flow");
+        let outcome = verifier
+            .wait_for_answer("flow", timeout, async { Ok(answer.await.ok()) })
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::TimedOut));
+        assert!(verifier.pending.lock().unwrap().by_id.is_empty());
+
+        // The flow is cancelled by the other side.
+        let (_, answer) = add_pending(&verifier, "flow");
+        verifier.withdraw("flow");
+        let outcome = verifier
+            .wait_for_answer("flow", timeout, async { Ok(answer.await.ok()) })
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Over));
+
+        let (id, answer) = add_pending(&verifier, "flow");
+        verifier.answer(id, true, "the control socket".to_owned());
+        let outcome = verifier
+            .wait_for_answer("flow", timeout, async { Ok(answer.await.ok()) })
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            Outcome::Answered(Answer {
+                confirmed: true,
+                ..
+            })
+        ));
+
+        let (_, answer) = add_pending(&verifier, "flow");
+        verifier.shutdown.request();
+        let outcome = verifier
+            .wait_for_answer("flow", Duration::from_secs(60), async {
+                Ok(answer.await.ok())
+            })
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Interrupted));
+        assert!(verifier.pending.lock().unwrap().by_id.is_empty());
+    }
+}